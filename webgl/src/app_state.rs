@@ -2,7 +2,7 @@ use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
 use wasm_bindgen::JsCast;
 use web_sys::{HtmlCanvasElement, WebGl2RenderingContext, WebGlBuffer, WebGlProgram, console, WebGlVertexArrayObject, WebGlShader};
-use crate::{constants, input, misc, triangle_drawing};
+use crate::{constants, input, misc, room, triangle_drawing};
 use crate::triangle_drawing::TriangleDrawing;
 
 pub(crate) struct ApplicationState {
@@ -22,6 +22,11 @@ pub(crate) struct ApplicationState {
 
     pub draw_setting: u8,
     pub triangle_drawing: TriangleDrawing,
+
+    /// Where our avatar is, moved with the arrow keys
+    pub position: (f32, f32),
+    pub room: Option<room::Room>,
+    pub avatar_drawing: room::AvatarDrawing,
 }
 
 impl ApplicationState {
@@ -39,6 +44,14 @@ impl ApplicationState {
             .dyn_into::<WebGl2RenderingContext>()
             .unwrap();
         let mut triangle_drawing = triangle_drawing::TriangleDrawing::new();
+        let avatar_drawing = room::AvatarDrawing::new(&gl);
+        let room = constants::ROOM_URL.and_then(|url| match room::Room::connect(url) {
+            Ok(room) => Some(room),
+            Err(e) => {
+                misc::log(&format!("Unable to join room {}, Err {}", url, e));
+                None
+            }
+        });
         // let buffer_vertex = gl.create_buffer().unwrap();
         // let program = gl
         //     .create_program()
@@ -57,6 +70,9 @@ impl ApplicationState {
             // vao_color: None,
             draw_setting: constants::DEFAULT_DRAW_SETTING,
             triangle_drawing,
+            position: (0.0, 0.0),
+            room,
+            avatar_drawing,
         }
     }
 
@@ -161,6 +177,14 @@ impl ApplicationState {
             }
         }
 
+        let moves = [("arrowleft", (-1.0, 0.0)), ("arrowright", (1.0, 0.0)), ("arrowup", (0.0, 1.0)), ("arrowdown", (0.0, -1.0))];
+        for (key, (x, y)) in moves {
+            if keys.contains_key(key) {
+                self.position.0 = (self.position.0 + x * constants::AVATAR_SPEED).clamp(-1.0, 1.0);
+                self.position.1 = (self.position.1 + y * constants::AVATAR_SPEED).clamp(-1.0, 1.0);
+            }
+        }
+
         // TODO: figure out better way to pass along the initial setup flag
         if self.triangle_drawing.init_setup {
             self.init_setup = self.triangle_drawing.init_setup;
@@ -171,6 +195,31 @@ impl ApplicationState {
             self.triangle_drawing.update_init(self.init_setup);
         }
     }
+
+    /// Tells the room where we are, then draws everyone in it over whatever draw_setting drew
+    pub fn draw_avatars(&mut self) {
+        let others = match &mut self.room {
+            Some(room) => {
+                room.publish(protocol::Presence {
+                    x: self.position.0,
+                    y: self.position.1,
+                    state: String::new(),
+                });
+                let others = room.positions();
+                let id = room.id.get().map(|x| x.to_string()).unwrap_or("joining".to_string());
+                self.debug_stats.set_node_val("room", &format!("{} with {} others", id, room.avatars.borrow().len()));
+                others
+            }
+            None => vec![],
+        };
+
+        self.avatar_drawing.draw(&others, [0.2, 0.6, 1.0, 1.0]);
+        self.avatar_drawing.draw(&[self.position], [1.0, 0.8, 0.2, 1.0]);
+
+        // TriangleDrawing only binds its program and buffer when it's set up, so put them back
+        self.gl.use_program(Some(&self.triangle_drawing.program));
+        self.gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&self.triangle_drawing.buffer_vertex));
+    }
 }
 
 pub struct DebugStats {
@@ -225,6 +274,7 @@ impl DebugStats {
         self.create_node("canvas_size");
         self.create_node("input");
         self.create_node("msg");
+        self.create_node("room");
     }
 
     pub fn set_debug_msg(&self, msg: &str) {
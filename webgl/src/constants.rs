@@ -1,6 +1,30 @@
 use web_sys::WebGl2RenderingContext;
 
 pub const WS_URL: &str = "wss://nx-hoster-sandbox.taco.kennysbasement.com/ws_deflated";
+// Sent as ?token= for servers with tokens in their config
+pub const WS_TOKEN: Option<&str> = None;
+// Ping the server this often, reconnect if the pong takes longer than PONG_TIMEOUT_MS
+pub const PING_INTERVAL_MS: i32 = 15000;
+pub const PONG_TIMEOUT_MS: i32 = 5000;
+// Servers from before protocol::Op::Hello never answer it, stop waiting after this long
+pub const HELLO_TIMEOUT_MS: i32 = 5000;
+// Wait this long before sending a request the server refused for going over its limits again
+pub const REFUSED_RETRY_MS: i32 = 1000;
+// Ask for responses bigger than this in pieces so #msg can show how far along they are
+pub const CHUNK_BYTES: Option<usize> = Some(256 * 1024);
+// Local websocket/server, run with NX_DIR pointing at a folder of .nx files
+// pub const WS_URL: &str = "ws://localhost:3000/ws";
+// Everyone else connected to the same room is drawn on top of the triangles, None to play alone
+pub const ROOM_URL: Option<&str> = None;
+// Lobby of a local websocket/server
+// pub const ROOM_URL: Option<&str> = Some("ws://localhost:3000/room/lobby");
+// Send our position at most this often, and at least every ROOM_KEEPALIVE_MS so the server doesn't
+// drop us as idle while we stand still
+pub const ROOM_SEND_MS: f32 = 100.0;
+pub const ROOM_KEEPALIVE_MS: f32 = 10000.0;
+// Clip space, so the canvas is 2.0 across
+pub const AVATAR_SIZE: f32 = 0.05;
+pub const AVATAR_SPEED: f32 = 0.01;
 
 // Constants
 pub const FPS: u8 = 60u8;
@@ -4,6 +4,7 @@ mod websocket;
 mod input;
 mod app_state;
 mod triangle_drawing;
+mod room;
 
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -34,6 +35,7 @@ pub fn main() {
             3 => app_state.triangle_drawing.draw_tri_random(),
             _ => {},
         };
+        app_state.draw_avatars();
 
         request_animation_frame(f.borrow().as_ref().unwrap());
     }));
@@ -0,0 +1,194 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use crate::misc::{log, now};
+use crate::{constants, misc};
+use wasm_bindgen::{closure::Closure, JsCast};
+use web_sys::{WebGl2RenderingContext, WebGlBuffer, WebGlProgram, WebGlVertexArrayObject, WebSocket};
+
+/// Connection to the server's /room/{id}- sends where we are, keeps track of where everyone else is
+///
+/// Nothing here blocks the render loop, events are applied to avatars as they arrive and publish
+/// just drops the update if the socket isn't open (yet or any more)
+pub struct Room {
+    ws: WebSocket,
+    /// Ours, once the Welcome arrives
+    pub id: Rc<Cell<Option<u32>>>,
+    /// Everyone else in the room, None until they've sent a presence
+    pub avatars: Rc<RefCell<BTreeMap<u32, Option<protocol::Presence>>>>,
+    last_sent: Option<(f32, protocol::Presence)>,
+}
+
+impl Room {
+    pub fn connect(url: &str) -> Result<Room, String> {
+        let url = match constants::WS_TOKEN {
+            Some(token) => format!("{}?token={}", url, String::from(js_sys::encode_uri_component(token))),
+            None => url.to_string(),
+        };
+        let ws = WebSocket::new(&url).map_err(|e| format!("{:?}", e))?;
+        log(&format!("Joining room {}", url));
+        let id = Rc::new(Cell::new(None));
+        let avatars = Rc::new(RefCell::new(BTreeMap::new()));
+
+        let id_clone = Rc::clone(&id);
+        let avatars_clone = Rc::clone(&avatars);
+        let onmessage_callback = Closure::<dyn FnMut(_)>::new(move |e: web_sys::MessageEvent| {
+            let text = match e.data().as_string() {
+                Some(text) => text,
+                None => return,
+            };
+            match serde_json::from_str::<protocol::RoomEvent>(&text) {
+                Ok(event) => handle_event(&id_clone, &avatars_clone, event),
+                Err(e) => log(&format!("Unable to read room event {}, Err {:?}", text, e)),
+            }
+        });
+        ws.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
+        onmessage_callback.forget();
+
+        let avatars_clone = Rc::clone(&avatars);
+        let onclose_callback = Closure::<dyn FnMut(_)>::new(move |e: web_sys::CloseEvent| {
+            // 1013 is the room being full
+            log(&format!("Left room: {} {}", e.code(), e.reason()));
+            avatars_clone.borrow_mut().clear();
+        });
+        ws.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
+        onclose_callback.forget();
+
+        Ok(Room {
+            ws,
+            id,
+            avatars,
+            last_sent: None,
+        })
+    }
+
+    /// Sends presence at most every constants::ROOM_SEND_MS, and only when it changed unless it's
+    /// been constants::ROOM_KEEPALIVE_MS (the server drops clients that go quiet)
+    pub fn publish(&mut self, presence: protocol::Presence) {
+        if self.ws.ready_state() != WebSocket::OPEN {
+            return;
+        }
+        let time = now();
+        if let Some((sent_at, sent)) = &self.last_sent {
+            let elapsed = time - sent_at;
+            if elapsed < constants::ROOM_SEND_MS || (*sent == presence && elapsed < constants::ROOM_KEEPALIVE_MS) {
+                return;
+            }
+        }
+        match self.ws.send_with_str(&serde_json::to_string(&presence).unwrap()) {
+            Ok(()) => self.last_sent = Some((time, presence)),
+            Err(e) => log(&format!("Unable to send presence, Err {:?}", e)),
+        }
+    }
+
+    /// Positions of everyone else that has sent one
+    pub fn positions(&self) -> Vec<(f32, f32)> {
+        self.avatars.borrow().values().flatten().map(|x| (x.x, x.y)).collect()
+    }
+}
+
+fn handle_event(id: &Cell<Option<u32>>, avatars: &RefCell<BTreeMap<u32, Option<protocol::Presence>>>, event: protocol::RoomEvent) {
+    let mut avatars = avatars.borrow_mut();
+    match event {
+        protocol::RoomEvent::Welcome { id: own_id, members } => {
+            log(&format!("In room as {} with {} others", own_id, members.len()));
+            id.set(Some(own_id));
+            *avatars = members.into_iter().map(|x| (x.id, x.presence)).collect();
+        }
+        protocol::RoomEvent::Joined { id } => {
+            avatars.insert(id, None);
+        }
+        protocol::RoomEvent::Left { id } => {
+            avatars.remove(&id);
+        }
+        protocol::RoomEvent::Presence { id, presence } => {
+            avatars.insert(id, Some(presence));
+        }
+        protocol::RoomEvent::Error(e) => log(&format!("Room error: {}", e)),
+    }
+}
+
+/// Draws a square per avatar, in clip space like the triangles
+pub struct AvatarDrawing {
+    gl: WebGl2RenderingContext,
+    program: WebGlProgram,
+    buffer_vertex: WebGlBuffer,
+    vao_vertex: Option<WebGlVertexArrayObject>,
+}
+
+impl AvatarDrawing {
+    pub fn new(gl: &WebGl2RenderingContext) -> AvatarDrawing {
+        let glsl_v = r##"#version 300 es
+        in vec2 a_position;
+        void main() {
+            gl_Position = vec4(a_position, 0.0, 1.0);
+        }"##;
+
+        let glsl_f = r##"#version 300 es
+        precision highp float;
+        uniform vec4 u_color;
+        out vec4 outColor;
+        void main() {
+            outColor = u_color;
+        }
+        "##;
+        let shader_v = misc::create_shader(gl, WebGl2RenderingContext::VERTEX_SHADER, glsl_v).unwrap();
+        let shader_f = misc::create_shader(gl, WebGl2RenderingContext::FRAGMENT_SHADER, glsl_f).unwrap();
+        let program = misc::link_program(gl, &shader_v, &shader_f).unwrap();
+        let buffer_vertex = gl.create_buffer().unwrap();
+
+        let vao_vertex = gl.create_vertex_array();
+        gl.bind_vertex_array(vao_vertex.as_ref());
+        gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&buffer_vertex));
+        let att_a_position: u32 = gl.get_attrib_location(&program, "a_position") as u32;
+        gl.enable_vertex_attrib_array(att_a_position);
+        gl.vertex_attrib_pointer_with_i32(
+            att_a_position,
+            2,
+            WebGl2RenderingContext::FLOAT,
+            false,
+            0,
+            0,
+        );
+        gl.bind_vertex_array(None);
+
+        AvatarDrawing {
+            gl: gl.clone(),
+            program,
+            buffer_vertex,
+            vao_vertex,
+        }
+    }
+
+    /// One draw call for every position, each a constants::AVATAR_SIZE square centred on it
+    /// Leaves its own program and buffer bound
+    pub fn draw(&self, positions: &[(f32, f32)], color: [f32; 4]) {
+        if positions.is_empty() {
+            return;
+        }
+        let gl = &self.gl;
+        let half = constants::AVATAR_SIZE / 2.0;
+        let vertices: Vec<f32> = positions
+            .iter()
+            .flat_map(|(x, y)| {
+                let (l, r, b, t) = (x - half, x + half, y - half, y + half);
+                [l, b, r, b, l, t, l, t, r, b, r, t]
+            })
+            .collect();
+
+        gl.use_program(Some(&self.program));
+        gl.uniform4fv_with_f32_array(gl.get_uniform_location(&self.program, "u_color").as_ref(), &color);
+        gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&self.buffer_vertex));
+        unsafe {
+            gl.buffer_data_with_array_buffer_view(
+                WebGl2RenderingContext::ARRAY_BUFFER,
+                &js_sys::Float32Array::view(&vertices),
+                WebGl2RenderingContext::DYNAMIC_DRAW,
+            );
+        }
+        gl.bind_vertex_array(self.vao_vertex.as_ref());
+        gl.draw_arrays(WebGl2RenderingContext::TRIANGLES, 0, (vertices.len() / 2) as i32);
+        gl.bind_vertex_array(None);
+    }
+}
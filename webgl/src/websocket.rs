@@ -1,23 +1,480 @@
-use std::collections::{HashMap, HashSet};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
 use std::sync::{Arc, Mutex, OnceLock, RwLock};
 
 use crate::misc::{log, sleep, window};
-use nx::{NodeDataPopulated, NodeS, NodeSH, WSRequest};
-use web_sys::WebSocket;
-
-#[derive(Debug, Clone)]
-pub enum WSResponse {
-    Empty,
-    Ok(NodeSH),
-    Error(String),
-    Message(String),
+use crate::constants;
+use futures::future::join_all;
+use nx::{NodeS, NodeSH, WSRequest};
+use wasm_bindgen::{closure::Closure, JsCast};
+use web_sys::{HtmlAudioElement, WebSocket};
+
+/// constants::WS_URL with constants::WS_TOKEN and constants::CHUNK_BYTES on the end if they're set
+pub fn ws_url() -> String {
+    let mut params = vec![];
+    if let Some(token) = constants::WS_TOKEN {
+        params.push(format!("token={}", String::from(js_sys::encode_uri_component(token))));
+    }
+    if let Some(chunk_bytes) = constants::CHUNK_BYTES {
+        params.push(format!("chunk_bytes={}", chunk_bytes));
+    }
+    if params.is_empty() {
+        return constants::WS_URL.to_string();
+    }
+    let separator = if constants::WS_URL.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", constants::WS_URL, separator, params.join("&"))
+}
+
+/// Where the server at constants::WS_URL serves the sound at path over plain HTTP (its /audio route),
+/// with constants::WS_TOKEN on the end if it's set
+pub fn audio_url(path: &str) -> String {
+    let base = constants::WS_URL
+        .replacen("wss://", "https://", 1)
+        .replacen("ws://", "http://", 1);
+    let host_start = base.find("://").map(|x| x + 3).unwrap_or(0);
+    let host_end = base[host_start..].find('/').map(|x| host_start + x).unwrap_or(base.len());
+    let path: Vec<String> = path
+        .split('/')
+        .map(|x| String::from(js_sys::encode_uri_component(x)))
+        .collect();
+
+    let mut url = format!("{}/audio/{}", &base[..host_end], path.join("/"));
+    if let Some(token) = constants::WS_TOKEN {
+        url.push_str(&format!("?token={}", String::from(js_sys::encode_uri_component(token))));
+    }
+    url
+}
+
+/// The connection requests go out on
+///
+/// A heartbeat sends protocol::Op::Ping every constants::PING_INTERVAL_MS (browsers can't send
+/// ping frames), if the pong doesn't come back within constants::PONG_TIMEOUT_MS the WebSocket
+/// is swapped for a new one and every request still waiting on a response is sent again
+pub struct Socket {
+    ws: RefCell<WebSocket>,
+    pending: Arc<Mutex<PendingRequests>>,
+    closed: Cell<bool>,
+    /// The server's answer to our protocol::Op::Hello, None if it's too old to have one- those are
+    /// sent bare nx::WSRequests instead of protocol::Requests, see send_legacy
+    pub server: Option<protocol::Hello>,
+}
+
+/// Ops this side sends, what goes in our Hello
+const OPS: [&str; 6] = ["Get", "BundleMap", "Bitmap", "Audio", "Ping", "Hello"];
+
+impl Socket {
+    /// Err if the server says it can't talk to us (see protocol::check)
+    pub async fn connect(pending: &Arc<Mutex<PendingRequests>>) -> Result<Rc<Socket>, String> {
+        let mut socket = Socket {
+            ws: RefCell::new(open(pending).await?),
+            pending: Arc::clone(pending),
+            closed: Cell::new(false),
+            server: None,
+        };
+        socket.server = hello(&socket).await?;
+
+        let socket = Rc::new(socket);
+        if socket.supports("Ping") {
+            wasm_bindgen_futures::spawn_local(heartbeat(Rc::clone(&socket)));
+        }
+        Ok(socket)
+    }
+
+    /// Servers without a Hello are only trusted with Get
+    pub fn supports(&self, op: &str) -> bool {
+        match &self.server {
+            Some(server) => server.supports(op),
+            None => op == "Get",
+        }
+    }
+
+    fn send(&self, text: &str) -> Result<(), String> {
+        self.ws.borrow().send_with_str(text).map_err(|e| format!("{:?}", e))
+    }
+
+    /// Stops the heartbeat too, so the socket stays closed
+    pub fn close(&self) {
+        self.closed.set(true);
+        if let Err(e) = self.ws.borrow().close() {
+            log(&format!("Unable to close WS, Err {:?}", e));
+        }
+    }
+
+    async fn reconnect(&self) -> Result<(), String> {
+        let _ = self.ws.borrow().close();
+        let ws = open(&self.pending).await?;
+        *self.ws.borrow_mut() = ws;
+
+        let unanswered = self.pending.lock().unwrap().unanswered();
+        log(&format!("Reconnected, sending {} requests again", unanswered.len()));
+        for text in unanswered {
+            self.send(&text)?;
+        }
+        Ok(())
+    }
+}
+
+async fn heartbeat(socket: Rc<Socket>) {
+    loop {
+        sleep(constants::PING_INTERVAL_MS).await;
+        if socket.closed.get() {
+            return;
+        }
+
+        let id = socket.pending.lock().unwrap().register();
+        let ping = serde_json::to_string(&protocol::Request { id, op: protocol::Op::Ping }).unwrap();
+        let sent = socket.send(&ping).is_ok();
+
+        let mut waited = 0;
+        let mut answered = false;
+        while sent && !answered && waited < constants::PONG_TIMEOUT_MS {
+            sleep(250).await;
+            waited += 250;
+            answered = socket.pending.lock().unwrap().take(id).iter().any(|x| x.done);
+        }
+        if socket.closed.get() {
+            return;
+        }
+        if answered {
+            continue;
+        }
+
+        socket.pending.lock().unwrap().forget(id);
+        log(&format!("No pong in {} ms, reconnecting", constants::PONG_TIMEOUT_MS));
+        if let Err(e) = socket.reconnect().await {
+            log(&format!("Unable to reconnect, trying again next ping, Err {}", e));
+        }
+    }
+}
+
+/// Swaps Hellos with the server, Ok(None) if it doesn't answer in constants::HELLO_TIMEOUT_MS
+/// (servers from before Hello answer with an error that has no id, so nothing comes back for it)
+async fn hello(socket: &Socket) -> Result<Option<protocol::Hello>, String> {
+    let id = socket.pending.lock().unwrap().register();
+    let encodings = match constants::CHUNK_BYTES {
+        Some(_) => vec!["json", "chunks"],
+        None => vec!["json"],
+    };
+    send_request(
+        socket,
+        &protocol::Request {
+            id,
+            op: protocol::Op::Hello(protocol::Hello::new(&OPS, &encodings)),
+        },
+    );
+
+    let mut waited = 0;
+    while waited < constants::HELLO_TIMEOUT_MS {
+        sleep(250).await;
+        waited += 250;
+        let responses = socket.pending.lock().unwrap().take(id);
+        match responses.into_iter().find(|x| x.done).map(|x| x.result) {
+            Some(Ok(protocol::Payload::Hello(server))) => {
+                protocol::check(&server).map_err(|e| format!("Server can't talk to this build, {}", e))?;
+                let missing: Vec<&str> = OPS.iter().filter(|x| !server.supports(x)).copied().collect();
+                if !missing.is_empty() {
+                    log(&format!("Server is on protocol {} without {:?}, doing without", server.version, missing));
+                }
+                return Ok(Some(server));
+            }
+            Some(result) => return Err(format!("Expected a hello, got {:?}", result)),
+            None => {}
+        }
+    }
+
+    socket.pending.lock().unwrap().forget(id);
+    log("Server didn't answer hello, assuming it's from before protocol versions and only using Get");
+    Ok(None)
+}
+
+/// Opens a WebSocket to ws_url() that feeds responses into pending, once it's open
+async fn open(pending: &Arc<Mutex<PendingRequests>>) -> Result<WebSocket, String> {
+    let ws = WebSocket::new(&ws_url()).map_err(|e| format!("{:?}", e))?;
+    log(&format!("Attempting WS conn to {}", constants::WS_URL));
+    let pending_clone = Arc::clone(pending);
+
+    // /ws_deflated sends every response as a binary frame of raw deflate, /ws as text
+    ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
+    let onmessage_callback = Closure::<dyn FnMut(_)>::new(move |e: web_sys::MessageEvent| {
+        let str_msg = match e.data().as_string() {
+            Some(text) => text,
+            None => match inflate(&js_sys::Uint8Array::new(&e.data()).to_vec()) {
+                Ok(text) => text,
+                Err(e) => {
+                    log(&e);
+                    return;
+                }
+            },
+        };
+        handle_message(&pending_clone, &str_msg);
+    });
+    ws.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
+    onmessage_callback.forget();
+
+    let onerror_callback = Closure::<dyn FnMut(_)>::new(move |e: web_sys::ErrorEvent| {
+        log(&format!("error event: {:?}", e));
+    });
+    ws.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
+    onerror_callback.forget();
+
+    let onopen_callback = Closure::<dyn FnMut()>::new(move || {
+        log("WebSocket Opened");
+        log(&format!(
+            "Open Time: {:?}",
+            window().performance().unwrap().now()
+        ));
+    });
+    ws.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
+    onopen_callback.forget();
+
+    let onclose_callback = Closure::<dyn FnMut(_)>::new(move |e: web_sys::CloseEvent| {
+        log(&format!(
+            "Close Time: {:?}",
+            window().performance().unwrap().now()
+        ));
+        log(&format!("CLOSING WS: {} {}", e.code(), e.reason()));
+    });
+    ws.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
+    onclose_callback.forget();
+
+    loop {
+        match ws.ready_state() {
+            WebSocket::OPEN => {
+                log("WS is open and ready");
+                return Ok(ws);
+            }
+            WebSocket::CONNECTING => {
+                log("WS connecting");
+                sleep(250).await;
+            }
+            _ => {
+                log("WS closed");
+                return Err("WS closed before it opened".to_string());
+            }
+        }
+    }
+}
+
+/// A binary frame from /ws_deflated back into the text /ws would have sent
+fn inflate(data: &[u8]) -> Result<String, String> {
+    let bytes = miniz_oxide::inflate::decompress_to_vec(data).map_err(|e| format!("Unable to inflate frame, Err {:?}", e))?;
+    String::from_utf8(bytes).map_err(|e| format!("Inflated frame isn't text, Err {:?}", e))
+}
+
+/// Requests that have been sent, keyed by the id that goes out in protocol::Request
+/// Responses pile up under their id until whoever sent the request takes them
+pub struct PendingRequests {
+    next_id: u32,
+    responses: HashMap<u32, Vec<protocol::Response>>,
+    // What went out for each id, sent again if the connection is replaced
+    requests: HashMap<u32, String>,
+    // Responses arriving as protocol::Stream chunks, joined up here until the last one is in
+    incoming: HashMap<u32, Incoming>,
+    // Id and path of bare requests sent to a server from before protocol::Request, whose answers
+    // have no id- only one is sent at a time so the next answer is always for the front one
+    legacy: VecDeque<(u32, String)>,
+}
+
+struct Incoming {
+    path: String,
+    bytes: u32,
+    chunks: u32,
+    json: String,
+}
+
+impl PendingRequests {
+    pub fn new() -> PendingRequests {
+        PendingRequests {
+            next_id: 1,
+            responses: HashMap::new(),
+            requests: HashMap::new(),
+            incoming: HashMap::new(),
+            legacy: VecDeque::new(),
+        }
+    }
+
+    /// Remembers what went out for id until its done response is taken
+    pub fn sent(&mut self, id: u32, text: String) {
+        self.requests.insert(id, text);
+    }
+
+    /// Remembers a bare nx::WSRequest for path went out under id
+    pub fn sent_legacy(&mut self, id: u32, path: String) {
+        self.legacy.push_back((id, path));
+    }
+
+    /// Whether a bare request is still waiting on its answer
+    pub fn legacy_waiting(&self) -> bool {
+        !self.legacy.is_empty()
+    }
+
+    /// Resolves the oldest bare request with what came back for it- the node's JSON or the old
+    /// "ERROR ..." text. Returns false if no bare request is waiting
+    pub fn answer_legacy(&mut self, text: &str) -> bool {
+        let (id, path) = match self.legacy.pop_front() {
+            Some(request) => request,
+            None => return false,
+        };
+        let result = match text.strip_prefix("ERROR ") {
+            // Only not found is worth telling apart, a dependency missing is skipped quietly
+            Some(e) => match e.strip_prefix("Not found: ") {
+                Some(e) => Err(protocol::Error::NotFound(e.to_string())),
+                None => Err(protocol::Error::Internal(e.to_string())),
+            },
+            None => serde_json::from_str::<NodeSH>(text)
+                .map(protocol::Payload::Node)
+                .map_err(|e| protocol::Error::Internal(format!("Unable to deserialize {}, Err {:?}", path, e))),
+        };
+        self.resolve(protocol::Response {
+            id,
+            path,
+            result,
+            done: true,
+        })
+    }
+
+    /// Every request sent that hasn't had its done response yet
+    pub fn unanswered(&self) -> Vec<String> {
+        self.requests
+            .iter()
+            .filter(|(id, _)| !self.responses.get(id).is_some_and(|x| x.iter().any(|r| r.done)))
+            .map(|(_, text)| text.clone())
+            .collect()
+    }
+
+    /// Stops waiting on id, anything that arrives for it later is dropped
+    pub fn forget(&mut self, id: u32) {
+        self.responses.remove(&id);
+        self.requests.remove(&id);
+        self.incoming.remove(&id);
+    }
+
+    /// Reserves an id for a new request
+    pub fn register(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        self.responses.insert(id, vec![]);
+        id
+    }
+
+    /// Returns false if nothing is waiting on the response's id
+    pub fn resolve(&mut self, response: protocol::Response) -> bool {
+        match self.responses.get_mut(&response.id) {
+            Some(slot) => {
+                slot.push(response);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds a protocol::Stream frame to the response it belongs to, once the last chunk is in the
+    /// joined up response is resolved like any other
+    /// Returns the response's path and how much of it has arrived, in percent
+    pub fn stream(&mut self, frame: protocol::Stream) -> Result<(String, u32), String> {
+        match frame {
+            protocol::Stream::Start { id, path, bytes, chunks } => {
+                let progress = (path.clone(), 0);
+                self.incoming.insert(
+                    id,
+                    Incoming {
+                        path,
+                        bytes,
+                        chunks,
+                        json: String::with_capacity(bytes as usize),
+                    },
+                );
+                Ok(progress)
+            }
+            protocol::Stream::Chunk { id, index, data } => {
+                let incoming = match self.incoming.get_mut(&id) {
+                    Some(incoming) => incoming,
+                    None => return Err(format!("Got a chunk for unknown stream {}", id)),
+                };
+                incoming.json.push_str(&data);
+                let progress = (
+                    incoming.path.clone(),
+                    (incoming.json.len() as u64 * 100 / incoming.bytes.max(1) as u64) as u32,
+                );
+                if index + 1 < incoming.chunks {
+                    return Ok(progress);
+                }
+
+                let incoming = self.incoming.remove(&id).unwrap();
+                match serde_json::from_str::<protocol::Response>(&incoming.json) {
+                    Ok(response) => match self.resolve(response) {
+                        true => Ok(progress),
+                        false => Err(format!("Got response for unknown request {}", id)),
+                    },
+                    Err(e) => Err(format!("Unable to deserialize streamed response {}, Err {:?}", id, e)),
+                }
+            }
+        }
+    }
+
+    /// Removes and returns every response for id that has arrived so far
+    /// The id is forgotten once the response marked done has been taken
+    pub fn take(&mut self, id: u32) -> Vec<protocol::Response> {
+        let responses = match self.responses.get_mut(&id) {
+            None => return vec![],
+            Some(slot) => std::mem::take(slot),
+        };
+        if responses.iter().any(|x| x.done) {
+            self.responses.remove(&id);
+            self.requests.remove(&id);
+        }
+        responses
+    }
+}
+
+/// Routes a text frame from the server to whichever request is waiting on it
+pub fn handle_message(pending: &Arc<Mutex<PendingRequests>>, str_msg: &str) {
+    if let Ok(frame) = serde_json::from_str::<protocol::Stream>(str_msg) {
+        let result = match pending.lock() {
+            Ok(mut s) => s.stream(frame),
+            Err(e) => Err(format!("Unable to get lock on pending requests, Err {:?}", e)),
+        };
+        match result {
+            Ok((_, 100)) => show_progress(""),
+            Ok((path, pct)) => show_progress(&format!("Downloading {} {}%", path, pct)),
+            Err(e) => log(&e),
+        }
+    } else if let Ok(response) = serde_json::from_str::<protocol::Response>(str_msg) {
+        let id = response.id;
+        let mut got_lock = pending.try_lock();
+        while let Err(_) = got_lock {
+            log("Unable to get lock on pending requests, trying again");
+            got_lock = pending.try_lock();
+        }
+        if !got_lock.unwrap().resolve(response) {
+            log(&format!("Got response for unknown request {}", id));
+        }
+    } else if pending.lock().unwrap().answer_legacy(str_msg) {
+        // Answer to a bare request, from a server that predates protocol::Request
+    } else if str_msg.starts_with("{") {
+        log(&format!("Unable to deserialize response {}", str_msg));
+    } else {
+        // Errors and messages without a request id can't be matched up, just print to console
+        log(&format!("Other MESSAGE ONLY {}", str_msg));
+    }
+}
+
+/// Puts text in the #msg overlay, in the text node the app keeps there if it has made one already
+fn show_progress(text: &str) {
+    if let Ok(Some(ele)) = window().document().unwrap().query_selector("#msg") {
+        match ele.last_child() {
+            Some(node) => node.set_node_value(Some(text)),
+            None => ele.set_text_content(Some(text)),
+        }
+    }
 }
 
 // Always expects either a 3 or 4 length path parameter
 pub async fn get_data_if_missing_hashmap(
-    ws: &WebSocket,
+    ws: &Socket,
     path: &[String],
-    tnh: Arc<Mutex<WSResponse>>,
+    pending: Arc<Mutex<PendingRequests>>,
     complete_hash_map: &OnceLock<RwLock<NodeSH>>,
 ) -> Result<(), String> {
     log(&format!("Getting file {:?}", path));
@@ -44,282 +501,357 @@ pub async fn get_data_if_missing_hashmap(
             nx::WSRequest {
                 path: path.join("/").clone(),
             },
-            tnh.clone(),
+            pending.clone(),
             complete_hash_map,
         )
-        .await?;
+        .await
+        .map_err(|e| e.to_string())?;
     }
     log(&format!("Finished {:?}", path));
     Ok(())
 }
 
 pub async fn get_full_img_file(
-    ws: &WebSocket,
+    ws: &Socket,
     path: String,
-    tnh: Arc<Mutex<WSResponse>>,
+    pending: Arc<Mutex<PendingRequests>>,
     complete_hash_map: &OnceLock<RwLock<NodeSH>>,
 ) -> Result<(), String> {
-    match get_img_file_hashmap(ws, nx::WSRequest { path }, tnh.clone(), complete_hash_map).await {
+    match get_img_file_hashmap(ws, nx::WSRequest { path }, pending.clone(), complete_hash_map).await {
         Ok(dep) => {
             log(&format!("{:?}", dep));
-            for path in dep {
-                get_img_file_hashmap(ws, nx::WSRequest { path }, tnh.clone(), complete_hash_map)
-                    .await
-                    .unwrap();
-            }
+            get_dependencies(ws, dep, pending, complete_hash_map).await;
             Ok(())
         }
         Err(e) => Err(format!("Error: {}", e)),
     }
 }
 
+/// Asks the server for the map img and everything it depends on in one go (protocol::Op::BundleMap)
+/// Servers without BundleMap get asked for each img file in turn instead
 pub async fn get_map_file_hashmap(
-    ws: &WebSocket,
+    ws: &Socket,
     map_id: &str,
-    tnh: Arc<Mutex<WSResponse>>,
+    pending: Arc<Mutex<PendingRequests>>,
     complete_hash_map: &OnceLock<RwLock<NodeSH>>,
 ) -> Result<(), String> {
-    match get_img_file_hashmap(
+    let start = window().performance().unwrap().now();
+    let map_path = protocol::map_path(map_id);
+    if !ws.supports("BundleMap") {
+        return get_full_img_file(ws, map_path, pending, complete_hash_map).await;
+    }
+    let id = pending.lock().unwrap().register();
+    send_request(
         ws,
-        nx::WSRequest {
-            path: format!(
-                "Map.nx/Map/Map{}/{}.img",
-                map_id.chars().next().unwrap(),
-                map_id
-            ),
+        &protocol::Request {
+            id,
+            op: protocol::Op::BundleMap(map_id.to_string()),
         },
-        tnh.clone(),
-        complete_hash_map,
-    )
-    .await
-    {
-        Ok(dep) => {
-            log(&format!("{:?}", dep));
-            for path in dep {
-                get_img_file_hashmap(ws, nx::WSRequest { path }, tnh.clone(), complete_hash_map)
-                    .await
-                    .unwrap();
+    );
+
+    let mut done = false;
+    while !done {
+        sleep(250).await;
+        let responses = match pending.try_lock() {
+            Ok(mut s) => s.take(id),
+            Err(_) => vec![],
+        };
+
+        for response in responses {
+            done = done || response.done;
+            match response.result {
+                Ok(protocol::Payload::Node(node_data)) => {
+                    insert_node(complete_hash_map, &response.path, node_data);
+                    log(&format!(
+                        "Grabbed {} in {:?} ms",
+                        response.path,
+                        window().performance().unwrap().now() - start
+                    ));
+                }
+                Ok(_) => log(&format!("Expected a node for {}", response.path)),
+                Err(e) if response.path == map_path => return Err(format!("Error: {}", e)),
+                Err(e) => skip_dependency(&response.path, &e),
             }
-            Ok(())
         }
-        Err(e) => Err(format!("Error: {}", e)),
+    }
+    Ok(())
+}
+
+/// Requests every dependency at once- the server answers them in whatever order they finish
+async fn get_dependencies(
+    ws: &Socket,
+    dep: Vec<String>,
+    pending: Arc<Mutex<PendingRequests>>,
+    complete_hash_map: &OnceLock<RwLock<NodeSH>>,
+) {
+    let paths = dep.clone();
+    let requests = dep.into_iter().map(|path| {
+        get_img_file_hashmap(ws, nx::WSRequest { path }, pending.clone(), complete_hash_map)
+    });
+    for (path, result) in paths.iter().zip(join_all(requests).await) {
+        if let Err(e) = result {
+            skip_dependency(path, &e);
+        }
+    }
+}
+
+/// A map still draws without some of its dependencies so these are only logged
+fn skip_dependency(path: &str, e: &protocol::Error) {
+    match e {
+        protocol::Error::NotFound(_) | protocol::Error::FileNotLoaded(_) => {
+            log(&format!("Skipping {}, the server doesn't have it- {}", path, e))
+        }
+        protocol::Error::MalformedPath(_) | protocol::Error::PathTraversal(_) => {
+            log(&format!("Skipping {}, the img file asks for a bad path- {}", path, e))
+        }
+        _ => log(&format!("Unable to get dependency {}, Err {}", path, e)),
     }
 }
 
 // Gets img file and returns dependencies that the IMG file asks for (in Back, Tile, and Obj)
+// Requests the server refused for going over its rate limit are sent again a little later
 pub async fn get_img_file_hashmap(
-    ws: &WebSocket,
+    ws: &Socket,
     p: nx::WSRequest,
-    tnh: Arc<Mutex<WSResponse>>, // shorthand for tmp_node_holder
+    pending: Arc<Mutex<PendingRequests>>,
     complete_hash_map: &OnceLock<RwLock<NodeSH>>,
-) -> Result<Vec<String>, String> {
+) -> Result<Vec<String>, protocol::Error> {
     let start = window().performance().unwrap().now();
-    match serde_json::to_string(&p) {
-        Ok(payload) => {
-            match ws.send_with_str(&payload) {
-                Ok(_) => log(&format!("Request for {:?}", &p)),
-                Err(e) => log(&format!("Unable to request {:?}, Err {:?}", &p, e)),
-            };
+    let mut responses: Vec<protocol::Response> = vec![];
+
+    loop {
+        // Answers to bare requests don't say which request they're for, so they go one at a time
+        while ws.server.is_none() && pending.lock().unwrap().legacy_waiting() {
+            sleep(50).await;
         }
-        Err(e) => {
-            log(&format!("Unable to serialize {:?}, Err {:?}", &p, e));
-            panic!("Unable to serialize")
+        let id = pending.lock().unwrap().register();
+        match ws.server {
+            Some(_) => send_request(
+                ws,
+                &protocol::Request {
+                    id,
+                    op: protocol::Op::Get(nx::WSRequest { path: p.path.clone() }),
+                },
+            ),
+            None => send_legacy(ws, id, &p),
         }
-    };
 
-    let mut is_data_ready = false;
+        while !responses.iter().any(|x| x.done) {
+            // print(&format!("I am going to sleep to wait websocket to populate data {}", p.file.clone()));
+            sleep(250).await;
+            if let Ok(mut s) = pending.try_lock() {
+                responses.append(&mut s.take(id));
+            }
+        }
 
-    while !is_data_ready {
-        // print(&format!("I am going to sleep to wait websocket to populate data {}", p.file.clone()));
-        sleep(250).await;
-        is_data_ready = {
-            match tnh.try_lock() {
-                Ok(s) => match *s {
-                    WSResponse::Empty => false,
-                    WSResponse::Ok(_) => true,
-                    WSResponse::Error(_) => true,
-                    WSResponse::Message(_) => true,
-                },
-                Err(_) => false,
+        match responses.iter().find_map(|x| x.result.as_ref().err()) {
+            Some(protocol::Error::Refused(e)) => {
+                log(&format!("Server refused {}, trying again, {}", p.path, e));
+                responses.clear();
+                sleep(constants::REFUSED_RETRY_MS).await;
             }
-        };
+            _ => break,
+        }
     }
 
-    let mut imgs_to_grab: HashSet<String> = HashSet::new();
+    let mut imgs_to_grab: Vec<String> = vec![];
 
-    let mut tmp_h_m_clone = Arc::clone(&tnh);
-    {
-        let mut tmp_node_editor = tmp_h_m_clone.try_lock();
-        match tmp_node_editor {
-            Ok(s) => {
-                match (*s).clone() {
-                    WSResponse::Empty => {
-                        panic!("Impossible scenario- empty response");
-                    }
-                    WSResponse::Ok(node_data) => {
-                        // puts data in right spot
-                        let path = p.path.split("/");
-                        match complete_hash_map.get().unwrap().try_write() {
-                            Ok(mut existing) => {
-                                let mut current_path = &mut existing.children;
-                                let mut current_node = "";
-                                for (i, node) in path.clone().enumerate() {
-                                    current_node = node;
-                                    if !current_path.contains_key(node) {
-                                        let mut tmp_hash_map: HashMap<String, nx::NodeSH> =
-                                            HashMap::new();
-                                        // If statement here prevents double creation of node
-                                        if i != path.clone().collect::<Vec<&str>>().len() - 1 {
-                                            current_path.insert(
-                                                node.to_string(),
-                                                nx::NodeSH {
-                                                    data: nx::NodeDataPopulated::None,
-                                                    children: tmp_hash_map,
-                                                },
-                                            );
-                                        }
-                                    };
-
-                                    if i != path.clone().collect::<Vec<&str>>().len() - 1 {
-                                        current_path =
-                                            &mut current_path.get_mut(node).unwrap().children;
-                                    }
-                                }
-                                current_path.insert(current_node.to_string(), node_data.clone());
-                            }
-                            Err(e) => {
-                                panic!("Cannot obtain writer for complete_hash_map {}", e)
-                            }
-                        }
-
-                        log(&format!(
-                            "Grabbed {} in {:?} ms",
-                            p.path,
-                            window().performance().unwrap().now() - start
-                        ));
-
-                        // traverses through object and download dependencies
-                        match node_data.children.get("back") {
-                            None => {}
-                            Some(img_back) => {
-                                let layers_back = img_back
-                                    .children
-                                    .keys()
-                                    .filter(|x| x.parse::<u16>().is_ok())
-                                    .collect::<Vec<&String>>();
-
-                                for layer in layers_back {
-                                    let tmp_node = img_back.children.get(layer).unwrap();
-
-                                    match tmp_node.children.get("bS") {
-                                        None => {}
-                                        Some(b_s) => match &b_s.data {
-                                            NodeDataPopulated::String(s) => {
-                                                if s.len() > 0 {
-                                                    imgs_to_grab
-                                                        .insert(format!("Map.nx/Back/{}.img", *s));
-                                                }
-                                            }
-                                            _ => {
-                                                panic!("Unexpected other types!!!")
-                                            }
-                                        },
-                                    }
-                                }
-
-                                let layers = node_data
-                                    .children
-                                    .keys()
-                                    .filter(|x| x.parse::<u16>().is_ok())
-                                    .collect::<Vec<&String>>();
-
-                                for layer in layers {
-                                    match node_data.children[layer].children.get("obj") {
-                                        None => {}
-                                        Some(node) => {
-                                            let tmp_keys = node
-                                                .children
-                                                .keys()
-                                                .filter(|x| x.parse::<u16>().is_ok())
-                                                .collect::<Vec<&String>>();
-
-                                            for key in tmp_keys {
-                                                let tmp_node = node.children.get(key).unwrap();
-
-                                                imgs_to_grab.insert(format!(
-                                                    "Map.nx/Obj/{}.img",
-                                                    match &tmp_node.children.get("oS").unwrap().data
-                                                    {
-                                                        NodeDataPopulated::String(s) => {
-                                                            (*s).clone()
-                                                        }
-                                                        _ => {
-                                                            panic!("Unexpected other types!!!")
-                                                        }
-                                                    }
-                                                ));
-                                            }
-                                        }
-                                    }
-
-                                    // get tile img
-                                    if let Some(info) =
-                                        node_data.children[layer].children.get("info")
-                                    {
-                                        if let Some(node) = info.children.get("tS") {
-                                            match &node.data {
-                                                NodeDataPopulated::String(s) => {
-                                                    imgs_to_grab.insert(format!(
-                                                        "Map.nx/Tile/{}.img",
-                                                        (*s).clone()
-                                                    ));
-                                                }
-                                                _ => {
-                                                    panic!("Unexpected data type in info attribute")
-                                                }
-                                            }
-                                        }
-                                    }
-
-                                    match node_data.children["info"].children.get("bgm") {
-                                        None => {}
-                                        Some(info) => match &info.data {
-                                            NodeDataPopulated::String(s) => {
-                                                let parts = s.split("/").collect::<Vec<&str>>();
-                                                imgs_to_grab.insert(format!(
-                                                    "Sound.nx/{}.img/{}",
-                                                    parts[0], parts[1]
-                                                ));
-                                            }
-                                            _ => {
-                                                panic!("Missing data")
-                                            }
-                                        },
-                                    }
-                                }
-                                log(&format!("{:?}", imgs_to_grab));
-                            }
-                        }
-                    }
+    for response in responses {
+        match response.result {
+            Ok(protocol::Payload::Node(node_data)) => {
+                // traverses through object to find dependencies
+                imgs_to_grab = protocol::img_dependencies(&node_data);
+                insert_node(complete_hash_map, &p.path, node_data);
 
-                    WSResponse::Error(err) => {
-                        return Err(format!("{}", err));
-                    }
-                    WSResponse::Message(msg) => {
-                        log(&format!("Got message {}", msg));
-                    }
-                }
+                log(&format!(
+                    "Grabbed {} in {:?} ms",
+                    p.path,
+                    window().performance().unwrap().now() - start
+                ));
+                log(&format!("{:?}", imgs_to_grab));
+            }
+            Ok(_) => {
+                return Err(protocol::Error::Internal(format!("Expected a node for {}", p.path)));
             }
-            Err(e) => {
-                let error_string = format!("Error getting lock {}", e);
-                log(&error_string);
-                return Err(error_string);
+            Err(err) => {
+                return Err(err);
             }
         }
     }
-    let mut tmp_node_editor = tmp_h_m_clone.lock().unwrap();
-    *tmp_node_editor = WSResponse::Empty;
 
     log(" ");
-    Ok(imgs_to_grab.into_iter().collect::<Vec<String>>().clone())
+    Ok(imgs_to_grab)
+}
+
+/// Pixels of the bitmap node at path, already decompressed to RGBA by the server (protocol::Op::Bitmap)
+/// so they can go straight into tex_image_2d
+pub async fn get_bitmap(
+    ws: &Socket,
+    path: &str,
+    pending: Arc<Mutex<PendingRequests>>,
+) -> Result<protocol::Bitmap, protocol::Error> {
+    if !ws.supports("Bitmap") {
+        return Err(protocol::Error::InvalidRequest("Server has no Bitmap op".to_string()));
+    }
+    let start = window().performance().unwrap().now();
+    let id = pending.lock().unwrap().register();
+    send_request(
+        ws,
+        &protocol::Request {
+            id,
+            op: protocol::Op::Bitmap(protocol::BitmapRequest {
+                path: path.to_string(),
+                format: protocol::BitmapFormat::Rgba,
+                downscale: 1,
+            }),
+        },
+    );
+
+    let mut responses: Vec<protocol::Response> = vec![];
+    while !responses.iter().any(|x| x.done) {
+        sleep(250).await;
+        if let Ok(mut s) = pending.try_lock() {
+            responses.append(&mut s.take(id));
+        }
+    }
+    match responses.into_iter().find(|x| x.done).unwrap().result {
+        Ok(protocol::Payload::Bitmap(bitmap)) => {
+            log(&format!(
+                "Grabbed bitmap {} ({}x{}) in {:?} ms",
+                path,
+                bitmap.width,
+                bitmap.height,
+                window().performance().unwrap().now() - start
+            ));
+            Ok(bitmap)
+        }
+        Ok(_) => Err(protocol::Error::Internal(format!("Expected a bitmap for {}", path))),
+        Err(e) => Err(e),
+    }
+}
+
+/// Asks what the sound at path is (protocol::Op::Audio) then plays it on a loop from audio_url
+/// Browsers won't start audio before the user has interacted with the page, that's only logged
+pub async fn play_audio(
+    ws: &Socket,
+    path: &str,
+    pending: Arc<Mutex<PendingRequests>>,
+) -> Result<HtmlAudioElement, protocol::Error> {
+    if !ws.supports("Audio") {
+        return Err(protocol::Error::InvalidRequest("Server has no Audio op".to_string()));
+    }
+    let id = pending.lock().unwrap().register();
+    send_request(
+        ws,
+        &protocol::Request {
+            id,
+            op: protocol::Op::Audio(path.to_string()),
+        },
+    );
+
+    let mut responses: Vec<protocol::Response> = vec![];
+    while !responses.iter().any(|x| x.done) {
+        sleep(250).await;
+        if let Ok(mut s) = pending.try_lock() {
+            responses.append(&mut s.take(id));
+        }
+    }
+    let info = match responses.into_iter().find(|x| x.done).unwrap().result {
+        Ok(protocol::Payload::Audio(info)) => info,
+        Ok(_) => return Err(protocol::Error::Internal(format!("Expected audio for {}", path))),
+        Err(e) => return Err(e),
+    };
+    log(&format!(
+        "Playing {} ({}, {} bytes, {})",
+        path,
+        info.mime,
+        info.bytes,
+        match info.duration_ms {
+            Some(ms) => format!("{:.1}s", ms as f64 / 1000.0),
+            None => "unknown length".to_string(),
+        }
+    ));
+
+    let audio = HtmlAudioElement::new_with_src(&audio_url(path))
+        .map_err(|e| protocol::Error::Internal(format!("Unable to make audio element, Err {:?}", e)))?;
+    audio.set_loop(true);
+    match audio.play() {
+        Ok(promise) => {
+            if let Err(e) = wasm_bindgen_futures::JsFuture::from(promise).await {
+                log(&format!("Unable to start {} until the page is clicked, Err {:?}", path, e));
+            }
+        }
+        Err(e) => log(&format!("Unable to play {}, Err {:?}", path, e)),
+    }
+    Ok(audio)
+}
+
+fn send_request(ws: &Socket, p: &protocol::Request) {
+    match serde_json::to_string(p) {
+        Ok(payload) => {
+            ws.pending.lock().unwrap().sent(p.id, payload.clone());
+            match ws.send(&payload) {
+                Ok(_) => log(&format!("Request for {:?}", p)),
+                Err(e) => log(&format!("Unable to request {:?}, Err {:?}", p, e)),
+            };
+        }
+        Err(e) => {
+            log(&format!("Unable to serialize {:?}, Err {:?}", p, e));
+            panic!("Unable to serialize")
+        }
+    };
+}
+
+/// A Get for servers from before protocol::Request- just the nx::WSRequest, its answer is matched
+/// up by PendingRequests::answer_legacy
+fn send_legacy(ws: &Socket, id: u32, p: &nx::WSRequest) {
+    let payload = match serde_json::to_string(p) {
+        Ok(payload) => payload,
+        Err(e) => {
+            log(&format!("Unable to serialize {:?}, Err {:?}", p, e));
+            panic!("Unable to serialize")
+        }
+    };
+    ws.pending.lock().unwrap().sent_legacy(id, p.path.clone());
+    match ws.send(&payload) {
+        Ok(_) => log(&format!("Bare request for {:?}", p)),
+        Err(e) => log(&format!("Unable to request {:?}, Err {:?}", p, e)),
+    };
+}
+
+/// Puts node_data in the right spot of complete_hash_map, creating parent nodes along the way
+fn insert_node(complete_hash_map: &OnceLock<RwLock<NodeSH>>, path: &str, node_data: NodeSH) {
+    let path = path.split("/");
+    match complete_hash_map.get().unwrap().try_write() {
+        Ok(mut existing) => {
+            let mut current_path = &mut existing.children;
+            let mut current_node = "";
+            for (i, node) in path.clone().enumerate() {
+                current_node = node;
+                if !current_path.contains_key(node) {
+                    let mut tmp_hash_map: HashMap<String, nx::NodeSH> =
+                        HashMap::new();
+                    // If statement here prevents double creation of node
+                    if i != path.clone().collect::<Vec<&str>>().len() - 1 {
+                        current_path.insert(
+                            node.to_string(),
+                            nx::NodeSH {
+                                data: nx::NodeDataPopulated::None,
+                                children: tmp_hash_map,
+                            },
+                        );
+                    }
+                };
+
+                if i != path.clone().collect::<Vec<&str>>().len() - 1 {
+                    current_path =
+                        &mut current_path.get_mut(node).unwrap().children;
+                }
+            }
+            current_path.insert(current_node.to_string(), node_data);
+        }
+        Err(e) => {
+            panic!("Cannot obtain writer for complete_hash_map {}", e)
+        }
+    }
 }
@@ -1,6 +1,17 @@
 use web_sys::WebGl2RenderingContext;
 
 pub const WS_URL: &str = "wss://nx-hoster-sandbox.taco.kennysbasement.com/ws_deflated";
+// Sent as ?token= for servers with tokens in their config
+pub const WS_TOKEN: Option<&str> = None;
+// Ping the server this often, reconnect if the pong takes longer than PONG_TIMEOUT_MS
+pub const PING_INTERVAL_MS: i32 = 15000;
+pub const PONG_TIMEOUT_MS: i32 = 5000;
+// Servers from before protocol::Op::Hello never answer it, stop waiting after this long
+pub const HELLO_TIMEOUT_MS: i32 = 5000;
+// Wait this long before sending a request the server refused for going over its limits again
+pub const REFUSED_RETRY_MS: i32 = 1000;
+// Ask for responses bigger than this in pieces so #msg can show how far along they are
+pub const CHUNK_BYTES: Option<usize> = Some(256 * 1024);
 
 // Constants
 pub const FPS: u8 = 60u8;
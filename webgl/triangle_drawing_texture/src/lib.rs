@@ -11,8 +11,8 @@ use std::sync::{Arc, Mutex, OnceLock, RwLock};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::{closure::Closure, JsCast, JsValue};
 use wasm_bindgen_futures::{js_sys, JsFuture};
-use web_sys::{console, HtmlCanvasElement, WebGl2RenderingContext, WebGlBuffer, WebGlProgram, WebGlShader, WebGlTexture, WebGlUniformLocation, WebSocket};
-use websocket::WSResponse;
+use web_sys::{console, HtmlCanvasElement, WebGl2RenderingContext, WebGlBuffer, WebGlProgram, WebGlShader, WebGlTexture, WebGlUniformLocation};
+use websocket::PendingRequests;
 
 static COMPLETE_HASH_MAP: OnceLock<RwLock<NodeSH>> = OnceLock::new();
 
@@ -107,14 +107,14 @@ pub async unsafe fn main() {
 
     gl.clear_color(0.08, 0.08, 0.08, 1.0);
     gl.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT | WebGl2RenderingContext::DEPTH_BUFFER_BIT);
-    let tmp_node_holder: Arc<Mutex<WSResponse>> = Arc::new(Mutex::new(WSResponse::Empty));
+    let pending_requests: Arc<Mutex<PendingRequests>> = Arc::new(Mutex::new(PendingRequests::new()));
 
-    let ws = websocket(&tmp_node_holder).await;
+    let socket = websocket::Socket::connect(&pending_requests).await.expect("Cannot open WS");
 
     match websocket::get_full_img_file(
-        &ws,
+        &socket,
         "UI.nx/MapLogin.img".to_string(),
-        Arc::clone(&tmp_node_holder),
+        Arc::clone(&pending_requests),
         &COMPLETE_HASH_MAP,
     )
     .await
@@ -122,7 +122,31 @@ pub async unsafe fn main() {
         Ok(_) => {}
         Err(e) => print(&format!("Cannot dl file {}", e)),
     }
-    ws.close().unwrap();
+
+    // Login screen music, kept around so it keeps playing after start returns
+    let bgm = {
+        let complete = COMPLETE_HASH_MAP.get().unwrap().read().unwrap();
+        complete
+            .children
+            .get("UI.nx")
+            .and_then(|x| x.children.get("MapLogin.img"))
+            .map(protocol::img_dependencies)
+            .unwrap_or_default()
+            .into_iter()
+            .find(|x| x.starts_with("Sound.nx/"))
+    };
+    if let Some(bgm) = bgm {
+        match websocket::play_audio(&socket, &bgm, Arc::clone(&pending_requests)).await {
+            Ok(audio) => std::mem::forget(audio),
+            Err(e) => print(&format!("Unable to play {}, Err {}", bgm, e)),
+        }
+    }
+
+    // Pixels come from the server already decompressed, when it can
+    let signboard = load_bitmap(&socket, "Map.nx/Obj/login.img/Title/signboard/0/0", Arc::clone(&pending_requests)).await;
+    let world_select = load_bitmap(&socket, "Map.nx/Obj/login.img/WorldSelect/signboard/0/0", Arc::clone(&pending_requests)).await;
+    let effect = load_bitmap(&socket, "Map.nx/Obj/login.img/Title/effect/0/0", Arc::clone(&pending_requests)).await;
+    socket.close();
 
     // FPS Counter in HTML https://webgl2fundamentals.org/webgl/lessons/webgl-text-html.html
     let fps_ele = document.query_selector("#fps").unwrap().unwrap();
@@ -144,43 +168,10 @@ pub async unsafe fn main() {
     let g = f.clone();
 
     //Set up bitmap once, save on compute time a bit
-    let bitmap: Vec<u8>;
-    let w: u16;
-    let h: u16;
-    let mut origin: (f32, f32) = (0.0, 0.0);
-
-    let node = COMPLETE_HASH_MAP.get().unwrap().read().unwrap();
-    // print(&format!("{:?}", node.children["UI.nx"].children["MapLogin.img"].children.keys()));
-    // print(&format!("{:?}", node.children["Map.nx"].children["Obj"].children["login.img"].children["Title"].children["signboard"].children["0"].children.keys()));
-    let signboard_node = &node.children["Map.nx"].children["Obj"].children["login.img"].children
-        ["Title"]
-        .children["signboard"]
-        .children["0"]
-        .children["0"];
-    match signboard_node.data {
-        NodeDataPopulated::Bitmap {
-            data: _,
-            width,
-            height,
-        } => {
-            bitmap = signboard_node.data.decompress().unwrap();
-            w = width.clone(); // 368 px
-            h = height.clone(); // 236 px
-        }
-        _ => {
-            panic!("Signboard node is not populated");
-        }
-    }
-
-    match signboard_node.children["origin"].data {
-        NodeDataPopulated::Vector(x, y) => {
-            origin.0 = x as f32;
-            origin.1 = y as f32;
-        }
-        _ => {
-            panic!("Missing origin");
-        }
-    }
+    let w = signboard.width; // 368 px
+    let h = signboard.height; // 236 px
+    let origin: (f32, f32) = (signboard.origin.0 as f32, signboard.origin.1 as f32);
+    let bitmap = signboard.data;
 
     let min = 1f32;
     let f_max = 2000f32;
@@ -215,8 +206,8 @@ pub async unsafe fn main() {
     // but again, whatever
     let buf_insta = setup_inst_buffer(&gl, &program, &coords);
 
-    let other_tex = setup_tex2(&gl);
-    let other_tex2 = setup_tex3(&gl);
+    let other_tex = setup_tex2(&gl, &world_select);
+    let other_tex2 = setup_tex3(&gl, &effect);
 
     // Below allows transparency to work
     // http://learnwebgl.brown37.net/11_advanced_rendering/alpha_blending.html
@@ -407,43 +398,7 @@ pub fn upload_tex(gl: &WebGl2RenderingContext, tex: &WebGlTexture, w: u16, h: u1
         .expect("Cannot generate tex");
 }
 
-pub fn setup_tex2(gl: &WebGl2RenderingContext) -> WebGlTexture {
-    let bitmap: Vec<u8>;
-    let w: u16;
-    let h: u16;
-    let mut origin: (f32, f32) = (0.0, 0.0);
-    let node = COMPLETE_HASH_MAP.get().unwrap().read().unwrap();
-    // print(&format!("{:?}", node.children["UI.nx"].children["MapLogin.img"].children.keys()));
-    // print(&format!("{:?}", node.children["Map.nx"].children["Obj"].children["login.img"].children["Title"].children["signboard"].children["0"].children.keys()));
-    let signboard_node = &node.children["Map.nx"].children["Obj"].children["login.img"].children
-        ["WorldSelect"]
-        .children["signboard"]
-        .children["0"]
-        .children["0"];
-    match signboard_node.data {
-        NodeDataPopulated::Bitmap {
-            data: _,
-            width,
-            height,
-        } => {
-            bitmap = signboard_node.data.decompress().unwrap();
-            w = width.clone(); // 368 px
-            h = height.clone(); // 236 px
-        }
-        _ => {
-            panic!("Signboard node is not populated");
-        }
-    }
-
-    match signboard_node.children["origin"].data {
-        NodeDataPopulated::Vector(x, y) => {
-            origin.0 = x as f32;
-            origin.1 = y as f32;
-        }
-        _ => {
-            panic!("Missing origin");
-        }
-    }
+pub fn setup_tex2(gl: &WebGl2RenderingContext, bitmap: &protocol::Bitmap) -> WebGlTexture {
     gl.active_texture(WebGl2RenderingContext::TEXTURE0);
 
     let tex = gl.create_texture().unwrap();
@@ -458,55 +413,19 @@ pub fn setup_tex2(gl: &WebGl2RenderingContext) -> WebGlTexture {
         constants::TARGET,
         constants::LEVEL,
         constants::INTERNAL_FORMAT,
-        w as i32,
-        h as i32,
+        bitmap.width as i32,
+        bitmap.height as i32,
         constants::BORDER,
         constants::SRC_FORMAT,
         constants::SRC_TYPE,
-        Some(&bitmap),
+        Some(&bitmap.data),
     )
         .expect("Cannot generate tex");
 
     tex
 }
 
-pub fn setup_tex3(gl: &WebGl2RenderingContext) -> WebGlTexture {
-    let bitmap: Vec<u8>;
-    let w: u16;
-    let h: u16;
-    let mut origin: (f32, f32) = (0.0, 0.0);
-    let node = COMPLETE_HASH_MAP.get().unwrap().read().unwrap();
-    // print(&format!("{:?}", node.children["UI.nx"].children["MapLogin.img"].children.keys()));
-    // print(&format!("{:?}", node.children["Map.nx"].children["Obj"].children["login.img"].children["Title"].children["signboard"].children["0"].children.keys()));
-    let signboard_node = &node.children["Map.nx"].children["Obj"].children["login.img"].children
-        ["Title"]
-        .children["effect"]
-        .children["0"]
-        .children["0"];
-    match signboard_node.data {
-        NodeDataPopulated::Bitmap {
-            data: _,
-            width,
-            height,
-        } => {
-            bitmap = signboard_node.data.decompress().unwrap();
-            w = width.clone(); // 368 px
-            h = height.clone(); // 236 px
-        }
-        _ => {
-            panic!("Signboard node is not populated");
-        }
-    }
-
-    match signboard_node.children["origin"].data {
-        NodeDataPopulated::Vector(x, y) => {
-            origin.0 = x as f32;
-            origin.1 = y as f32;
-        }
-        _ => {
-            panic!("Missing origin");
-        }
-    }
+pub fn setup_tex3(gl: &WebGl2RenderingContext, bitmap: &protocol::Bitmap) -> WebGlTexture {
     gl.active_texture(WebGl2RenderingContext::TEXTURE0);
 
     let tex = gl.create_texture().unwrap();
@@ -521,18 +440,44 @@ pub fn setup_tex3(gl: &WebGl2RenderingContext) -> WebGlTexture {
         constants::TARGET,
         constants::LEVEL,
         constants::INTERNAL_FORMAT,
-        w as i32,
-        h as i32,
+        bitmap.width as i32,
+        bitmap.height as i32,
         constants::BORDER,
         constants::SRC_FORMAT,
         constants::SRC_TYPE,
-        Some(&bitmap),
+        Some(&bitmap.data),
     )
         .expect("Cannot generate tex");
 
     tex
 }
 
+/// The bitmap at path from the server's Bitmap op, or decompressed here from the node already in
+/// COMPLETE_HASH_MAP if the server doesn't have it
+async fn load_bitmap(socket: &websocket::Socket, path: &str, pending: Arc<Mutex<PendingRequests>>) -> protocol::Bitmap {
+    match websocket::get_bitmap(socket, path, pending).await {
+        Ok(bitmap) => return bitmap,
+        Err(e) => print(&format!("Decompressing {} here instead, Err {}", path, e)),
+    }
+    let complete = COMPLETE_HASH_MAP.get().unwrap().read().unwrap();
+    let node = path.split('/').fold(&*complete, |node, name| &node.children[name]);
+    let (width, height) = match node.data {
+        NodeDataPopulated::Bitmap { width, height, .. } => (width, height),
+        _ => panic!("{} is not a bitmap", path),
+    };
+    let origin = match node.children.get("origin").map(|x| &x.data) {
+        Some(NodeDataPopulated::Vector(x, y)) => (*x, *y),
+        _ => panic!("Missing origin"),
+    };
+    protocol::Bitmap {
+        format: protocol::BitmapFormat::Rgba,
+        width,
+        height,
+        origin,
+        data: node.data.decompress().unwrap(),
+    }
+}
+
 pub fn set_active_tex(gl: &WebGl2RenderingContext, gl_tex: &WebGlTexture) {
     gl.active_texture(WebGl2RenderingContext::TEXTURE0);
     gl.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(gl_tex));
@@ -1360,96 +1305,6 @@ pub unsafe fn draw_triangle(gl: &WebGl2RenderingContext, program: &WebGlProgram)
     gl.draw_arrays(WebGl2RenderingContext::TRIANGLES, 0, 6);
 }
 
-pub async fn websocket(tnh: &Arc<Mutex<WSResponse>>) -> WebSocket {
-    let ws = WebSocket::new(constants::WS_URL).unwrap();
-    print(&format!("Attempting WS conn to {}", constants::WS_URL));
-    let tmp_h_m_clone = Arc::clone(tnh);
-
-    let onmessage_callback = Closure::<dyn FnMut(_)>::new(move |e: web_sys::MessageEvent| {
-        let str_msg = e.data().into_serde::<String>().unwrap();
-        let mut got_lock = tmp_h_m_clone.try_lock();
-
-        while let Err(_) = got_lock {
-            print("Unable to get lock on TMP_NODE, trying again");
-            got_lock = tmp_h_m_clone.try_lock();
-        }
-        let mut tmp_node_changer = got_lock.unwrap();
-
-        if str_msg.starts_with("{") {
-            let ser_img = serde_json::from_str::<nx::NodeSH>(&str_msg).unwrap();
-
-            match *tmp_node_changer {
-                WSResponse::Empty => {}
-                WSResponse::Ok(_) => print("Existing WSResponse found- overwriting"),
-                WSResponse::Error(_) => print("Existing WSResponse found- overwriting"),
-                WSResponse::Message(_) => print("Existing WSResponse found- overwriting"),
-            }
-            *tmp_node_changer = WSResponse::Ok(ser_img.clone());
-        } else if str_msg.starts_with("ERROR") {
-            *tmp_node_changer = WSResponse::Error(str_msg.clone());
-        } else {
-            // print(&format!("Other MESSAGE ONLY {}", str_msg));
-            // Don't need to keep these messages, just print to console
-            *tmp_node_changer = WSResponse::Message(str_msg.clone());
-        }
-    });
-    ws.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
-    onmessage_callback.forget();
-
-    let onerror_callback = Closure::<dyn FnMut(_)>::new(move |e: web_sys::ErrorEvent| {
-        print(&format!("error event: {:?}", e));
-    });
-    ws.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
-    onerror_callback.forget();
-
-    let onopen_callback = Closure::<dyn FnMut()>::new(move || {
-        print("WebSocket Opened");
-        print(&format!(
-            "Open Time: {:?}",
-            window().performance().unwrap().now()
-        ));
-    });
-    ws.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
-    onopen_callback.forget();
-
-    let onclose_callback = Closure::<dyn FnMut(_)>::new(move |e: web_sys::CloseEvent| {
-        print(&format!(
-            "Close Time: {:?}",
-            window().performance().unwrap().now()
-        ));
-        print(&format!("CLOSING WS: {:?}", e.to_string()));
-    });
-    ws.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
-    onclose_callback.forget();
-
-    let mut ws_state = ws.ready_state();
-    while ws_state != WebSocket::OPEN {
-        // print("Waiting for WebSocket to open");
-        ws_state = ws.ready_state();
-
-        match ws_state {
-            WebSocket::CLOSING => {
-                print("WS closing");
-                panic!("WS closed");
-            }
-            WebSocket::CLOSED => {
-                print("WS closed");
-                panic!("WS closed");
-            }
-            WebSocket::OPEN => print("WS is open and ready"),
-            WebSocket::CONNECTING => {
-                print("WS connecting");
-                sleep(250).await;
-            }
-            _ => {
-                unreachable!()
-            }
-        }
-    }
-
-    ws
-}
-
 pub async fn sleep(millis: i32) {
     let mut cb = |resolve: js_sys::Function, _reject: js_sys::Function| {
         window()
@@ -0,0 +1,333 @@
+//! Load test- N connections each asking for the same set of paths as fast as the server answers
+//!
+//! client bench --map 100000000 --connections 50 --requests 200 --json > before.json
+//!
+//! The server rate limits each connection (--requests-per-second, 50 by default), start it with a
+//! higher one or most of the report is Refused errors
+use crate::connection::Connection;
+use protocol::Op;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::Barrier;
+use std::time::{Duration, Instant};
+
+pub struct Options {
+    pub connections: usize,
+    /// Per connection
+    pub requests: usize,
+    pub json: bool,
+}
+
+/// What's printed at the end, as JSON with --json so runs can be compared
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub connections: usize,
+    /// Connections that couldn't be opened, their requests aren't in requests or errors
+    pub failed_connections: usize,
+    pub paths: usize,
+    pub requests: usize,
+    pub errors: usize,
+    /// protocol::Error variant -> how many, "Connection" for connections that broke part way
+    pub errors_by_kind: BTreeMap<&'static str, usize>,
+    pub seconds: f64,
+    pub requests_per_second: f64,
+    pub latency_ms: Latency,
+    pub first_error: Option<String>,
+}
+
+/// Of the requests that got an answer, errors included
+#[derive(Debug, Default, Serialize)]
+pub struct Latency {
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+#[derive(Default)]
+struct Worker {
+    latencies: Vec<Duration>,
+    requests: usize,
+    errors: BTreeMap<&'static str, usize>,
+    first_error: Option<String>,
+    failed: bool,
+}
+
+impl Worker {
+    fn error(&mut self, kind: &'static str, e: String) {
+        *self.errors.entry(kind).or_default() += 1;
+        self.first_error.get_or_insert(e);
+    }
+}
+
+/// open is called once per connection, and once more to work out a map's paths
+pub fn run(
+    open: impl Fn() -> Result<Connection, String> + Sync,
+    map: Option<&str>,
+    mut paths: Vec<String>,
+    options: &Options,
+) -> Result<(), String> {
+    if let Some(map) = map {
+        let mut connection = open()?;
+        let deps = dependency_set(&mut connection, map);
+        connection.close();
+        paths.extend(deps?);
+    }
+    if paths.is_empty() {
+        return Err("Nothing to ask for, give a --map or some --path".to_string());
+    }
+    eprintln!(
+        "{} connections, {} requests each over {} paths",
+        options.connections,
+        options.requests,
+        paths.len()
+    );
+
+    // Everyone connects first so the clock only covers requests
+    let barrier = Barrier::new(options.connections + 1);
+    let (workers, elapsed) = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..options.connections)
+            .map(|i| {
+                let (open, paths, barrier) = (&open, &paths, &barrier);
+                scope.spawn(move || worker(open, paths, i, options.requests, barrier))
+            })
+            .collect();
+        barrier.wait();
+        let start = Instant::now();
+        let workers: Vec<Worker> = handles.into_iter().map(|x| x.join().unwrap_or_default()).collect();
+        (workers, start.elapsed())
+    });
+
+    let report = report(workers, paths.len(), options.connections, elapsed);
+    match options.json {
+        true => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).map_err(|e| format!("Unable to serialize report, Err {:?}", e))?
+        ),
+        false => print(&report),
+    }
+    Ok(())
+}
+
+/// The map's img and everything it needs, the same fixpoint mirror fetches
+fn dependency_set(connection: &mut Connection, map: &str) -> Result<Vec<String>, String> {
+    let map_path = protocol::map_path(map);
+    let mut queue = VecDeque::from([map_path.clone()]);
+    let mut paths = BTreeSet::new();
+    while let Some(path) = queue.pop_front() {
+        if paths.contains(&path) {
+            continue;
+        }
+        match crate::tree(connection, &path, 4) {
+            Ok(node) => queue.extend(protocol::img_dependencies(&node)),
+            Err(e) if path == map_path => return Err(e),
+            // Still asked for, it's part of what a browser loading the map would ask for
+            Err(e) => eprintln!("{}", e),
+        }
+        paths.insert(path);
+    }
+    Ok(paths.into_iter().collect())
+}
+
+/// Starts at a different path per connection so they're not all asking for the same thing at once
+fn worker(
+    open: &(impl Fn() -> Result<Connection, String> + Sync),
+    paths: &[String],
+    i: usize,
+    requests: usize,
+    barrier: &Barrier,
+) -> Worker {
+    let mut result = Worker::default();
+    let connection = open();
+    barrier.wait();
+    let mut connection = match connection {
+        Ok(connection) => connection,
+        Err(e) => {
+            result.failed = true;
+            result.first_error = Some(e);
+            return result;
+        }
+    };
+
+    for n in 0..requests {
+        let path = &paths[(i + n) % paths.len()];
+        let start = Instant::now();
+        let responses = connection.request(Op::Get(nx::WSRequest { path: path.clone() }));
+        result.requests += 1;
+        let responses = match responses {
+            Ok(responses) => responses,
+            Err(e) => {
+                // The connection's gone, nothing more will get through on it
+                result.error("Connection", e);
+                break;
+            }
+        };
+        result.latencies.push(start.elapsed());
+        if let Some(e) = responses.into_iter().find_map(|x| x.result.err()) {
+            result.error(kind(&e), format!("{}: {}", path, e));
+        }
+    }
+    connection.close();
+    result
+}
+
+fn report(workers: Vec<Worker>, paths: usize, connections: usize, elapsed: Duration) -> Report {
+    let mut latencies: Vec<Duration> = vec![];
+    let mut errors_by_kind: BTreeMap<&'static str, usize> = BTreeMap::new();
+    let (mut requests, mut failed_connections, mut first_error) = (0, 0, None);
+    for worker in workers {
+        latencies.extend(worker.latencies);
+        requests += worker.requests;
+        for (kind, count) in worker.errors {
+            *errors_by_kind.entry(kind).or_default() += count;
+        }
+        failed_connections += worker.failed as usize;
+        first_error = first_error.or(worker.first_error);
+    }
+    latencies.sort();
+
+    let seconds = elapsed.as_secs_f64();
+    Report {
+        connections,
+        failed_connections,
+        paths,
+        requests,
+        errors: errors_by_kind.values().sum(),
+        errors_by_kind,
+        seconds,
+        requests_per_second: if seconds > 0.0 { requests as f64 / seconds } else { 0.0 },
+        latency_ms: Latency {
+            p50: percentile(&latencies, 50.0),
+            p95: percentile(&latencies, 95.0),
+            p99: percentile(&latencies, 99.0),
+            max: latencies.last().map(ms).unwrap_or_default(),
+        },
+        first_error,
+    }
+}
+
+fn kind(e: &protocol::Error) -> &'static str {
+    match e {
+        protocol::Error::NotFound(_) => "NotFound",
+        protocol::Error::MalformedPath(_) => "MalformedPath",
+        protocol::Error::PathTraversal(_) => "PathTraversal",
+        protocol::Error::FileNotLoaded(_) => "FileNotLoaded",
+        protocol::Error::InvalidRequest(_) => "InvalidRequest",
+        protocol::Error::Refused(_) => "Refused",
+        protocol::Error::Internal(_) => "Internal",
+    }
+}
+
+/// Nearest rank, latencies sorted
+fn percentile(latencies: &[Duration], p: f64) -> f64 {
+    if latencies.is_empty() {
+        return 0.0;
+    }
+    let rank = (p / 100.0 * latencies.len() as f64).ceil() as usize;
+    ms(&latencies[rank.clamp(1, latencies.len()) - 1])
+}
+
+fn ms(duration: &Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn print(report: &Report) {
+    println!(
+        "{} requests over {} connections ({} failed to connect) in {:.2}s",
+        report.requests, report.connections, report.failed_connections, report.seconds
+    );
+    let kinds: Vec<String> = report.errors_by_kind.iter().map(|(kind, count)| format!("{} {}", kind, count)).collect();
+    match kinds.is_empty() {
+        true => println!("{:.1} requests/s, no errors", report.requests_per_second),
+        false => println!("{:.1} requests/s, {} errors ({})", report.requests_per_second, report.errors, kinds.join(", ")),
+    }
+    let latency = &report.latency_ms;
+    println!(
+        "latency ms p50 {:.2}  p95 {:.2}  p99 {:.2}  max {:.2}",
+        latency.p50, latency.p95, latency.p99, latency.max
+    );
+    if let Some(e) = &report.first_error {
+        println!("first error: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(x: impl IntoIterator<Item = u64>) -> Vec<Duration> {
+        x.into_iter().map(Duration::from_millis).collect()
+    }
+
+    #[test]
+    fn percentiles_are_nearest_rank() {
+        let latencies = millis(1..=10);
+        assert_eq!(percentile(&latencies, 50.0), ms(&latencies[4]));
+        assert_eq!(percentile(&latencies, 90.0), ms(&latencies[8]));
+        assert_eq!(percentile(&latencies, 95.0), ms(&latencies[9]));
+        assert_eq!(percentile(&latencies, 99.0), ms(&latencies[9]));
+        // Rank 0 would be before the first sample
+        assert_eq!(percentile(&latencies, 0.0), ms(&latencies[0]));
+    }
+
+    #[test]
+    fn percentiles_of_nothing_and_one() {
+        assert_eq!(percentile(&[], 50.0), 0.0);
+        let one = millis([7]);
+        for p in [0.0, 50.0, 99.0, 100.0] {
+            assert_eq!(percentile(&one, p), ms(&one[0]), "p{}", p);
+        }
+    }
+
+    #[test]
+    fn report_adds_up_workers() {
+        let mut slow = Worker {
+            latencies: millis([30, 10]),
+            requests: 2,
+            ..Default::default()
+        };
+        slow.error("NotFound", "Not found: a".to_string());
+        let mut fast = Worker {
+            latencies: millis([20]),
+            requests: 1,
+            ..Default::default()
+        };
+        fast.error("NotFound", "Not found: b".to_string());
+        fast.error("Refused", "Refused: slow down".to_string());
+        let failed = Worker {
+            failed: true,
+            ..Default::default()
+        };
+
+        let report = report(vec![slow, fast, failed], 4, 3, Duration::from_secs(2));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "connections": 3,
+                "failed_connections": 1,
+                "paths": 4,
+                "requests": 3,
+                "errors": 3,
+                "errors_by_kind": {"NotFound": 2, "Refused": 1},
+                "seconds": 2.0,
+                "requests_per_second": 1.5,
+                "latency_ms": {
+                    "p50": ms(&Duration::from_millis(20)),
+                    "p95": ms(&Duration::from_millis(30)),
+                    "p99": ms(&Duration::from_millis(30)),
+                    "max": ms(&Duration::from_millis(30)),
+                },
+                "first_error": "Not found: a",
+            })
+        );
+    }
+
+    #[test]
+    fn report_of_no_requests() {
+        let report = report(vec![], 1, 0, Duration::ZERO);
+        assert_eq!((report.requests, report.errors, report.requests_per_second), (0, 0, 0.0));
+        assert_eq!(report.latency_ms.max, 0.0);
+        assert!(report.first_error.is_none());
+    }
+}
@@ -0,0 +1,235 @@
+use nx::NodeSH;
+use protocol::{Op, Payload, Response};
+use std::net::TcpStream;
+use std::time::Duration;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{connect, Message, WebSocket};
+
+pub type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+/// How long a request gets before it's given up on
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the server gets to answer Hello before it's taken to be one from before Hello
+const HELLO_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Ops the CLI sends, what goes in our Hello
+const OPS: [&str; 5] = ["Get", "Children", "Tree", "Ping", "Hello"];
+
+/// Request/response connection to /ws (JSON) or /wsb (bincode)
+pub struct Connection {
+    socket: Socket,
+    binary: bool,
+    next_id: u32,
+    /// The server's Hello, None if it's too old to have one
+    pub server: Option<protocol::Hello>,
+}
+
+impl Connection {
+    /// Connects and swaps Hellos, Err if the server can't talk to this build
+    pub fn open(url: &str, binary: bool, token: Option<&str>) -> Result<Connection, String> {
+        let route = if binary { "wsb" } else { "ws" };
+        let socket = open(url, route, token)?;
+        let mut connection = Connection {
+            socket,
+            binary,
+            next_id: 1,
+            server: None,
+        };
+
+        let encodings = if binary { ["bincode"] } else { ["json"] };
+        connection.set_read_timeout(HELLO_TIMEOUT)?;
+        let hello = connection.exchange(Op::Hello(protocol::Hello::new(&OPS, &encodings)));
+        connection.set_read_timeout(READ_TIMEOUT)?;
+        connection.server = match hello {
+            Ok(mut responses) => match responses.remove(0).result {
+                Ok(protocol::Payload::Hello(server)) => {
+                    protocol::check(&server).map_err(|e| format!("Server can't talk to this build, {}", e))?;
+                    Some(server)
+                }
+                result => return Err(format!("Expected a hello, got {:?}", result)),
+            },
+            // Servers from before Hello can't read it, they answer with an error or not at all
+            Err(e) => {
+                eprintln!("Server didn't answer hello ({}), only using Get", e);
+                None
+            }
+        };
+        Ok(connection)
+    }
+
+    /// Servers without a Hello only answer Get
+    fn supports(&self, op: &str) -> bool {
+        match &self.server {
+            Some(server) => server.supports(op),
+            None => op == "Get",
+        }
+    }
+
+    /// Sends op and reads responses until the one marked done
+    /// Err if the server doesn't do op, the connection fails or the server answers with something
+    /// that isn't for us
+    pub fn request(&mut self, op: Op) -> Result<Vec<Response>, String> {
+        if !self.supports(op.name()) {
+            return Err(match self.server {
+                Some(_) => format!("The server doesn't do {}", op.name()),
+                None => format!("The server is from before Hello and only answers Get, not {}", op.name()),
+            });
+        }
+        match (self.server.is_some(), op) {
+            (true, op) => self.exchange(op),
+            (false, Op::Get(request)) => self.exchange_bare(request),
+            (false, op) => Err(format!("Unable to send {} as a bare request", op.name())),
+        }
+    }
+
+    /// Sends op as a protocol::Request and reads responses to its id until the one marked done
+    fn exchange(&mut self, op: Op) -> Result<Vec<Response>, String> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = protocol::Request { id, op };
+
+        let msg = match self.binary {
+            true => Message::Binary(bincode::serialize(&request).map_err(|e| format!("Unable to serialize, Err {:?}", e))?),
+            false => Message::Text(serde_json::to_string(&request).map_err(|e| format!("Unable to serialize, Err {:?}", e))?),
+        };
+        self.socket.send(msg).map_err(|e| format!("Unable to send, Err {}", e))?;
+
+        let mut responses = vec![];
+        loop {
+            let response: Response = match self.socket.read() {
+                Ok(Message::Text(text)) if text.starts_with("ERROR") => return Err(text),
+                Ok(Message::Text(text)) => {
+                    serde_json::from_str(&text).map_err(|e| format!("Unable to read {}, Err {:?}", text, e))?
+                }
+                Ok(Message::Binary(bin)) => {
+                    bincode::deserialize(&bin).map_err(|e| format!("Unable to read response, Err {:?}", e))?
+                }
+                Ok(Message::Close(frame)) => return Err(format!("Server closed the connection {:?}", frame)),
+                Ok(_) => continue,
+                Err(e) => return Err(format!("Unable to read, Err {}", e)),
+            };
+            if response.id == protocol::UNKNOWN_ID {
+                return Err(match response.result {
+                    Err(e) => e.to_string(),
+                    Ok(_) => "Response without an id".to_string(),
+                });
+            }
+            if response.id != id {
+                continue;
+            }
+            let done = response.done;
+            responses.push(response);
+            if done {
+                return Ok(responses);
+            }
+        }
+    }
+
+    /// Servers from before Hello only read a bare nx::WSRequest and answer it with the node (JSON,
+    /// or a bincode Result<NodeSH, String> on /wsb) or "ERROR ..." text, in the order they were
+    /// sent- made into the Response a newer server would have sent
+    fn exchange_bare(&mut self, request: nx::WSRequest) -> Result<Vec<Response>, String> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let msg = match self.binary {
+            true => Message::Binary(bincode::serialize(&request).map_err(|e| format!("Unable to serialize, Err {:?}", e))?),
+            false => Message::Text(serde_json::to_string(&request).map_err(|e| format!("Unable to serialize, Err {:?}", e))?),
+        };
+        self.socket.send(msg).map_err(|e| format!("Unable to send, Err {}", e))?;
+
+        let result = loop {
+            match self.socket.read() {
+                Ok(Message::Text(text)) => match text.strip_prefix("ERROR ") {
+                    Some(e) => break Err(bare_error(e)),
+                    None => match serde_json::from_str::<NodeSH>(&text) {
+                        Ok(node) => break Ok(Payload::Node(node)),
+                        Err(e) => return Err(format!("Unable to read {}, Err {:?}", request.path, e)),
+                    },
+                },
+                Ok(Message::Binary(bin)) => {
+                    break bincode::deserialize::<Result<NodeSH, String>>(&bin)
+                        .map_err(|e| format!("Unable to read {}, Err {:?}", request.path, e))?
+                        .map(Payload::Node)
+                        .map_err(|e| bare_error(&e))
+                }
+                Ok(Message::Close(frame)) => return Err(format!("Server closed the connection {:?}", frame)),
+                Ok(_) => continue,
+                Err(e) => return Err(format!("Unable to read, Err {}", e)),
+            }
+        };
+        Ok(vec![Response {
+            id,
+            path: request.path,
+            result,
+            done: true,
+        }])
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> Result<(), String> {
+        if let MaybeTlsStream::Plain(stream) = self.socket.get_ref() {
+            stream
+                .set_read_timeout(Some(timeout))
+                .map_err(|e| format!("Can't set read timeout, Err {}", e))?;
+        }
+        Ok(())
+    }
+
+    pub fn close(mut self) {
+        let _ = self.socket.close(None);
+        // Flush the close frame and wait for the server's answer
+        while self.socket.read().is_ok() {}
+    }
+}
+
+/// Connects to route on the server at url, with token on the end if there is one
+/// Fails if the server's protocol::VERSION_HEADER and protocol::SCHEMA_HEADER say it can't talk to us
+pub fn open(url: &str, route: &str, token: Option<&str>) -> Result<Socket, String> {
+    let mut url = format!("{}/{}", url.trim_end_matches('/'), route);
+    if let Some(token) = token {
+        // Tokens can have &, + or spaces in them
+        let query = form_urlencoded::Serializer::new(String::new()).append_pair("token", token).finish();
+        url.push_str(&format!("?{}", query));
+    }
+    let (socket, response) = connect(&url).map_err(|e| format!("Can't connect to {}, Err {}", url, e))?;
+    check_version(response.headers()).map_err(|e| format!("Server doesn't speak this client's protocol, {}", e))?;
+
+    if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+        stream
+            .set_read_timeout(Some(READ_TIMEOUT))
+            .map_err(|e| format!("Can't set read timeout, Err {}", e))?;
+    }
+    Ok(socket)
+}
+
+/// Error text from a server from before Hello, which only says "Not found: ..." in a way worth
+/// telling apart
+fn bare_error(text: &str) -> protocol::Error {
+    match text.strip_prefix("Not found: ") {
+        Some(e) => protocol::Error::NotFound(e.to_string()),
+        None => protocol::Error::Internal(text.to_string()),
+    }
+}
+
+/// A server from before the headers existed is let through- Hello (or the lack of one) sorts it out
+fn check_version(headers: &tungstenite::http::HeaderMap) -> Result<(), String> {
+    let header = |name: &str| headers.get(name).and_then(|x| x.to_str().ok());
+    let version = match header(protocol::VERSION_HEADER) {
+        Some(version) => version
+            .parse::<u32>()
+            .map_err(|e| format!("Unreadable version {:?}, Err {:?}", version, e))?,
+        None => return Ok(()),
+    };
+    let schema = header(protocol::SCHEMA_HEADER)
+        .and_then(|x| u64::from_str_radix(x, 16).ok())
+        .unwrap_or_default();
+
+    // The headers only carry the server's version, a newer one is given the benefit of the doubt
+    protocol::check(&protocol::Hello {
+        version,
+        min_version: 0,
+        schema,
+        ops: vec![],
+        encodings: vec![],
+    })
+}
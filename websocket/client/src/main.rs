@@ -1,23 +1,193 @@
-use tungstenite::{connect, Message};
+//! Looks at what a websocket/server is serving from a terminal
+//!
+//! client get Map.nx/Obj/login.img
+//! client ls Map.nx/Obj
+//! client tree Map.nx/Map/Map1/100000000.img --depth 2
+//! client deps 100000000
+//! client mirror --map 100000000 --out mirror/
+//! client bench --map 100000000 --connections 50
+mod bench;
+mod connection;
+mod mirror;
+mod wst;
+
+use clap::{Parser, Subcommand};
+use connection::Connection;
+use nx::{NodeDataPopulated, NodeSH};
+use protocol::{Op, Payload};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(about = "Browses the .nx files a websocket/server is serving")]
+struct Args {
+    /// Server to connect to, the route is added on the end
+    #[arg(long, global = true, default_value = "ws://localhost:3000")]
+    url: String,
+
+    /// Use /wsb (bincode) instead of /ws (JSON)
+    #[arg(long, global = true)]
+    binary: bool,
+
+    /// For servers with tokens in their config
+    #[arg(long, global = true, env = "NX_TOKEN")]
+    token: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Node at path with everything under it, as pretty JSON
+    Get { path: String },
+    /// Names, kinds and child counts of the node's children
+    Ls { path: String },
+    /// Node at path and what's under it, down to depth levels
+    Tree {
+        path: String,
+        #[arg(long, default_value_t = 1)]
+        depth: u16,
+    },
+    /// Img files a map asks for, the same ones the browser loads with it
+    Deps { map_id: String },
+    /// Downloads a map and everything it depends on into out, picking up where the last run stopped
+    Mirror {
+        #[arg(long)]
+        map: String,
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Load test- every connection makes --requests Gets over the map's paths and any --path,
+    /// then latency percentiles, throughput and errors are printed
+    Bench {
+        #[arg(long)]
+        map: Option<String>,
+        #[arg(long = "path")]
+        paths: Vec<String>,
+        #[arg(long, default_value_t = 10)]
+        connections: usize,
+        /// Per connection
+        #[arg(long, default_value_t = 100)]
+        requests: usize,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Prints the /wst test stream until the server closes it
+    Wst,
+}
 
 fn main() {
-    let (mut socket, response) = connect("ws://localhost:3000/wst").expect("Can't connect");
+    let args = Args::parse();
+    let result = match &args.command {
+        Command::Wst => wst::run(&args.url, args.token.as_deref()),
+        Command::Bench {
+            map,
+            paths,
+            connections,
+            requests,
+            json,
+        } => bench::run(
+            || Connection::open(&args.url, args.binary, args.token.as_deref()),
+            map.as_deref(),
+            paths.clone(),
+            &bench::Options {
+                connections: *connections,
+                requests: *requests,
+                json: *json,
+            },
+        ),
+        command => Connection::open(&args.url, args.binary, args.token.as_deref()).and_then(|mut connection| {
+            let result = run(&mut connection, command);
+            connection.close();
+            result
+        }),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(connection: &mut Connection, command: &Command) -> Result<(), String> {
+    match command {
+        Command::Get { path } => {
+            let node = get(connection, Op::Get(nx::WSRequest { path: path.clone() }))?;
+            let json = serde_json::to_string_pretty(&node).map_err(|e| format!("Unable to print {}, Err {:?}", path, e))?;
+            println!("{}", json);
+        }
+        Command::Ls { path } => {
+            let children = match single(connection, Op::Children(path.clone()))? {
+                Payload::Children(children) => children,
+                payload => return Err(format!("Expected children, got {:?}", payload)),
+            };
+            let width = children.iter().map(|x| x.name.len()).max().unwrap_or(0);
+            for child in children {
+                println!("{:width$}  {:<7}  {}", child.name, format!("{:?}", child.kind), child.children, width = width);
+            }
+        }
+        Command::Tree { path, depth } => {
+            let node = tree(connection, path, *depth)?;
+            println!("{}", path);
+            print_tree(&node, 1, *depth);
+        }
+        Command::Deps { map_id } => {
+            // Deepest thing img_dependencies looks at is N/obj/M/oS
+            let node = tree(connection, &protocol::map_path(map_id), 4)?;
+            for dep in protocol::img_dependencies(&node) {
+                println!("{}", dep);
+            }
+        }
+        Command::Mirror { map, out } => mirror::run(connection, map, out)?,
+        Command::Bench { .. } | Command::Wst => unreachable!(),
+    }
+    Ok(())
+}
+
+/// The one response to op's payload, or its error
+fn single(connection: &mut Connection, op: Op) -> Result<Payload, String> {
+    let mut responses = connection.request(op)?;
+    let response = responses.remove(0);
+    response.result.map_err(|e| format!("{}: {}", response.path, e))
+}
 
-    println!("Connected to the server");
-    println!("Response HTTP code: {}", response.status());
-    println!("Response contains the following headers:");
-    for (header, _value) in response.headers() {
-        println!("* {header}");
+fn get(connection: &mut Connection, op: Op) -> Result<NodeSH, String> {
+    match single(connection, op)? {
+        Payload::Node(node) => Ok(node),
+        payload => Err(format!("Expected a node, got {:?}", payload)),
     }
+}
 
-    socket.send(Message::Text("Hello WebSocket".into())).unwrap();
-    loop {
-        let msg = socket.read();
-        if let Ok(Message::Binary(ref bin_data)) = msg {
-            let msg: nx::WSRequest = bincode::deserialize(&bin_data).expect("Failed to deserialize");
-            println!("Received message: {:?}", msg);
+fn tree(connection: &mut Connection, path: &str, depth: u16) -> Result<NodeSH, String> {
+    get(connection, Op::Tree(protocol::TreeRequest { path: path.to_string(), depth }))
+}
+
+/// Children sorted by name, two spaces in per level
+fn print_tree(node: &NodeSH, level: u16, depth: u16) {
+    if level > depth {
+        return;
+    }
+    let mut names: Vec<&String> = node.children.keys().collect();
+    names.sort();
+    for name in names {
+        let child = &node.children[name];
+        let indent = "  ".repeat(level as usize);
+        match value(&child.data) {
+            Some(value) => println!("{}{}: {}", indent, name, value),
+            None => println!("{}{}", indent, name),
         }
-        // println!("Received message: {:?}", msg);
+        print_tree(child, level + 1, depth);
+    }
+}
+
+fn value(data: &NodeDataPopulated) -> Option<String> {
+    match data {
+        NodeDataPopulated::None => None,
+        NodeDataPopulated::Integer(x) => Some(x.to_string()),
+        NodeDataPopulated::Float(x) => Some(x.to_string()),
+        NodeDataPopulated::String(x) => Some(format!("{:?}", x)),
+        NodeDataPopulated::Vector(x, y) => Some(format!("({}, {})", x, y)),
+        NodeDataPopulated::Bitmap { width, height, .. } => Some(format!("bitmap {}x{}", width, height)),
+        NodeDataPopulated::Audio(_) => Some("audio".to_string()),
     }
-    // socket.close(None);
-}
\ No newline at end of file
+}
@@ -0,0 +1,155 @@
+//! Downloads a map and every img file it needs so it can be served without the real game files
+//!
+//! out/
+//!   node/{path}- each img file as the JSON GET /node/{path} answers with, for a static host
+//!   nx/Map.nx, nx/Sound.nx...- everything in node/ as .nx files, for NX_DIR=out/nx server
+//!   manifest.json- what's been fetched, a second run only fetches what isn't there yet
+//!
+//! Several maps can be mirrored into the same out, the .nx files get all of them
+use crate::connection::Connection;
+use nx::NodeSH;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = "manifest.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    /// Map ids that have been mirrored with everything they need
+    maps: BTreeSet<String>,
+    /// Path -> what was written for it
+    files: BTreeMap<String, Entry>,
+    /// Path -> why the server couldn't give it to us, tried again next run
+    missing: BTreeMap<String, String>,
+    /// Set once every dependency has been fetched and the .nx files written
+    complete: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// Under out, eg node/Map.nx/Obj/login.img
+    file: String,
+    bytes: u64,
+    deps: Vec<String>,
+}
+
+impl Manifest {
+    fn load(out: &Path) -> Result<Manifest, String> {
+        match fs::read(out.join(MANIFEST)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| format!("Unable to read {}, Err {:?}", MANIFEST, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(format!("Unable to open {}, Err {:?}", MANIFEST, e)),
+        }
+    }
+
+    fn save(&self, out: &Path) -> Result<(), String> {
+        let json = serde_json::to_vec_pretty(self).map_err(|e| format!("Unable to serialize manifest, Err {:?}", e))?;
+        write(&out.join(MANIFEST), &json)
+    }
+}
+
+/// Fetches map_id's img, then the img files it depends on, then theirs... until there's nothing new
+pub fn run(connection: &mut Connection, map_id: &str, out: &Path) -> Result<(), String> {
+    fs::create_dir_all(out).map_err(|e| format!("Unable to create {:?}, Err {:?}", out, e))?;
+    let mut manifest = Manifest::load(out)?;
+    // Saved with the first thing fetched, a map that isn't there leaves the manifest alone
+    manifest.complete = false;
+
+    let map_path = protocol::map_path(map_id);
+    let mut queue = VecDeque::from([map_path.clone()]);
+    let mut seen = HashSet::new();
+    let mut nodes: HashMap<String, NodeSH> = HashMap::new();
+    let (mut fetched, mut resumed) = (0, 0);
+    while let Some(path) = queue.pop_front() {
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+        let node = match manifest.files.get(&path).and_then(|entry| read(out, entry)) {
+            Some(node) => {
+                resumed += 1;
+                node
+            }
+            None => match fetch(connection, &path) {
+                Ok(node) => {
+                    let entry = save(out, &path, &node)?;
+                    println!("{} {} bytes", path, entry.bytes);
+                    manifest.files.insert(path.clone(), entry);
+                    manifest.missing.remove(&path);
+                    manifest.save(out)?;
+                    fetched += 1;
+                    node
+                }
+                Err(e) if path == map_path => return Err(e),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    manifest.missing.insert(path.clone(), e);
+                    manifest.save(out)?;
+                    continue;
+                }
+            },
+        };
+        queue.extend(protocol::img_dependencies(&node));
+        nodes.insert(path, node);
+    }
+    println!("{} fetched, {} already there, {} missing", fetched, resumed, manifest.missing.len());
+
+    write_nx(out, &manifest, nodes)?;
+    manifest.maps.insert(map_id.to_string());
+    manifest.complete = true;
+    manifest.save(out)
+}
+
+fn fetch(connection: &mut Connection, path: &str) -> Result<NodeSH, String> {
+    protocol::check_path(path).map_err(|e| e.to_string())?;
+    crate::get(connection, protocol::Op::Get(nx::WSRequest { path: path.to_string() }))
+}
+
+/// The node written for entry, None if it's not there or not all there (eg the last run was
+/// stopped part way through writing it)
+fn read(out: &Path, entry: &Entry) -> Option<NodeSH> {
+    let bytes = fs::read(out.join(&entry.file)).ok()?;
+    if bytes.len() as u64 != entry.bytes {
+        return None;
+    }
+    serde_json::from_slice(&bytes).ok()
+}
+
+fn save(out: &Path, path: &str, node: &NodeSH) -> Result<Entry, String> {
+    let file = format!("node/{}", path.split('/').filter(|x| !x.is_empty()).collect::<Vec<_>>().join("/"));
+    let json = serde_json::to_vec(node).map_err(|e| format!("Unable to serialize {}, Err {:?}", path, e))?;
+    write(&out.join(&file), &json)?;
+    Ok(Entry {
+        file,
+        bytes: json.len() as u64,
+        deps: protocol::img_dependencies(node),
+    })
+}
+
+/// Every file in the manifest, not just this run's, as .nx files in out/nx
+fn write_nx(out: &Path, manifest: &Manifest, mut nodes: HashMap<String, NodeSH>) -> Result<(), String> {
+    let mut assets = nxwrite::Assets::new();
+    for (path, entry) in &manifest.files {
+        let node = match nodes.remove(path) {
+            Some(node) => node,
+            None => read(out, entry).ok_or(format!("{} is in the manifest but {} isn't readable", path, entry.file))?,
+        };
+        assets.insert(path, nxwrite::Node::from_node_sh(&node).map_err(|e| format!("Unable to convert {}, Err {}", path, e))?);
+    }
+    let dir = out.join("nx");
+    assets.write(&dir).map_err(|e| format!("Unable to write {:?}, Err {:?}", dir, e))?;
+    println!("Wrote {} to {:?}", assets.files.keys().cloned().collect::<Vec<_>>().join(", "), dir);
+    Ok(())
+}
+
+/// Writes next to path then renames, so path is never half written
+fn write(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Unable to create {:?}, Err {:?}", parent, e))?;
+    }
+    let mut part = PathBuf::from(path);
+    part.as_mut_os_string().push(".part");
+    fs::write(&part, bytes).map_err(|e| format!("Unable to write {:?}, Err {:?}", part, e))?;
+    fs::rename(&part, path).map_err(|e| format!("Unable to move {:?} to {:?}, Err {:?}", part, path, e))
+}
@@ -0,0 +1,72 @@
+use crate::connection::{self, Socket};
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+use tungstenite::{Error, Message};
+
+// Ping the server when nothing has come in for this long
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+// Reconnect if the pong takes longer than this
+const PONG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Prints the /wst test stream until the server closes it, reconnecting when it stops answering
+pub fn run(url: &str, token: Option<&str>) -> Result<(), String> {
+    let mut socket = open(url, token)?;
+    // When the unanswered ping went out
+    let mut waiting_since: Option<Instant> = None;
+    let mut last_read = Instant::now();
+    loop {
+        let msg = socket.read();
+        if msg.is_ok() {
+            last_read = Instant::now();
+        }
+        match msg {
+            Ok(Message::Binary(bin_data)) => {
+                let msg: nx::WSRequest = bincode::deserialize(&bin_data).expect("Failed to deserialize");
+                println!("Received message: {:?}", msg);
+            }
+            Ok(Message::Pong(_)) => waiting_since = None,
+            Ok(Message::Close(frame)) => println!("Server closed the connection {:?}", frame),
+            Err(Error::Io(ref e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                match waiting_since {
+                    Some(sent) if sent.elapsed() >= PONG_TIMEOUT => {
+                        println!("No pong in {:?}, reconnecting", PONG_TIMEOUT);
+                        let _ = socket.close(None);
+                        socket = open(url, token)?;
+                        waiting_since = None;
+                    }
+                    Some(_) => {}
+                    None if last_read.elapsed() < PING_INTERVAL => {}
+                    None => match socket.send(Message::Ping(vec![])) {
+                        Ok(_) => waiting_since = Some(Instant::now()),
+                        Err(e) => println!("Unable to ping, Err {:?}", e),
+                    },
+                }
+            }
+            Err(Error::ConnectionClosed | Error::AlreadyClosed) => return Ok(()),
+            Err(e) => {
+                println!("Unable to read, reconnecting, Err {:?}", e);
+                socket = open(url, token)?;
+                waiting_since = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Connects to /wst and asks for the test stream
+/// Reads time out every so often so run can keep the heartbeat going while the server is quiet
+fn open(url: &str, token: Option<&str>) -> Result<Socket, String> {
+    let mut socket = connection::open(url, "wst", token)?;
+    println!("Connected to the server");
+
+    if let tungstenite::stream::MaybeTlsStream::Plain(stream) = socket.get_ref() {
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .map_err(|e| format!("Can't set read timeout, Err {}", e))?;
+    }
+
+    socket
+        .send(Message::Text("Hello WebSocket".into()))
+        .map_err(|e| format!("Unable to send, Err {}", e))?;
+    Ok(socket)
+}
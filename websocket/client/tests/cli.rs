@@ -0,0 +1,116 @@
+mod common;
+
+use common::{OldServer, Server};
+use std::time::{Duration, Instant};
+
+#[test]
+fn get_prints_the_node_as_json() {
+    let server = Server::start(&[]);
+
+    for binary in [&[][..], &["--binary"][..]] {
+        let args = [binary, &["get", "Map.nx/Obj/login.img"]].concat();
+        let node: nx::NodeSH = serde_json::from_str(&server.stdout(&args)).unwrap();
+        let sprite = &node.children["obj"].children["0"];
+        assert!(matches!(&sprite.data, nx::NodeDataPopulated::Bitmap { width: 2, height: 2, data } if !data.is_empty()));
+    }
+
+    let output = server.client(&["get", "Map.nx/Obj/nope.img"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Not found"));
+}
+
+#[test]
+fn ls_lists_names_kinds_and_counts() {
+    let server = Server::start(&[]);
+
+    let names: Vec<String> = server
+        .stdout(&["ls", "Map.nx/Map/Map1/100000000.img"])
+        .lines()
+        .map(|x| x.split_whitespace().next().unwrap().to_string())
+        .collect();
+    assert_eq!(names, ["0", "back", "info"]);
+
+    let columns: Vec<Vec<String>> = server
+        .stdout(&["ls", "Map.nx/Obj/login.img/obj/0"])
+        .lines()
+        .map(|x| x.split_whitespace().map(str::to_string).collect())
+        .collect();
+    assert_eq!(columns, [["origin", "Vector", "0"]]);
+}
+
+#[test]
+fn tree_stops_at_depth() {
+    let server = Server::start(&[]);
+
+    assert_eq!(
+        server.stdout(&["tree", "Map.nx/Back/grassySoil.img", "--depth", "3"]),
+        "Map.nx/Back/grassySoil.img\n  back\n    0: bitmap 16x16\n      origin: (8, 16)\n"
+    );
+    assert_eq!(
+        server.stdout(&["tree", "Map.nx/Back/grassySoil.img", "--depth", "2"]),
+        "Map.nx/Back/grassySoil.img\n  back\n    0: bitmap 16x16\n"
+    );
+}
+
+#[test]
+fn deps_are_the_imgs_the_map_asks_for() {
+    let server = Server::start(&[]);
+
+    let deps: Vec<String> = server.stdout(&["deps", "100000000"]).lines().map(str::to_string).collect();
+    assert_eq!(
+        deps,
+        [
+            "Map.nx/Back/grassySoil.img",
+            "Map.nx/Obj/login.img",
+            "Map.nx/Tile/woodMarble.img",
+            "Sound.nx/Bgm00.img/GoPicnic",
+        ]
+    );
+
+    assert!(!server.client(&["deps", "999999999"]).status.success());
+}
+
+#[test]
+fn tokens_are_sent_percent_encoded() {
+    let token = "a b&c+d=e%f";
+    let server = Server::start(&["--tokens", token]);
+
+    assert!(server.stdout(&["--token", token, "ls", "Map.nx"]).contains("Obj"));
+
+    let output = server.client(&["ls", "Map.nx"]);
+    assert!(!output.status.success());
+    let output = server.client(&["--token", "a b", "ls", "Map.nx"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn servers_from_before_hello_only_answer_get() {
+    let server = OldServer::start(false);
+
+    for binary in [&[][..], &["--binary"][..]] {
+        let args = [binary, &["get", "Map.nx/Obj/login.img"]].concat();
+        let node: nx::NodeSH = serde_json::from_str(&server.stdout(&args)).unwrap();
+        assert!(node.children["obj"].children.contains_key("0"));
+
+        let output = server.client(&[binary, &["get", "Map.nx/Obj/nope.img"]].concat());
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Not found: Map.nx/Obj/nope.img"));
+    }
+
+    for (command, op) in [("ls", "Children"), ("tree", "Tree")] {
+        let output = server.client(&[command, "Map.nx/Obj/login.img"]);
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(&format!("only answers Get, not {}", op)), "{}", stderr);
+    }
+}
+
+#[test]
+fn servers_that_ignore_hello_are_not_waited_on_for_long() {
+    let server = OldServer::start(true);
+
+    let start = Instant::now();
+    let node: nx::NodeSH = serde_json::from_str(&server.stdout(&["get", "Map.nx/Obj/login.img"])).unwrap();
+    assert!(node.children.contains_key("obj"));
+    assert!(start.elapsed() < Duration::from_secs(10), "Took {:?}", start.elapsed());
+}
@@ -0,0 +1,188 @@
+//! Starts websocket/server on a free port against fixtures::sample() written out as .nx files, and
+//! runs the client binary at it
+//!
+//! OldServer stands in for a server from before Hello instead
+//!
+//! The server is another package so it's built here, once per test run, into CARGO_TARGET_TMPDIR
+#![allow(dead_code)]
+
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::OnceLock;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+
+pub struct Server {
+    pub url: String,
+    pub nx_dir: PathBuf,
+    child: Child,
+}
+
+/// Builds websocket/server, panicking with cargo's output if that fails
+fn server_binary() -> &'static Path {
+    static BINARY: OnceLock<PathBuf> = OnceLock::new();
+    BINARY.get_or_init(|| {
+        let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("server");
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("../server/Cargo.toml");
+        let output = Command::new(env!("CARGO"))
+            .args(["build", "--quiet", "--bin", "server", "--manifest-path"])
+            .arg(&manifest)
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .output()
+            .expect("Can't run cargo");
+        assert!(output.status.success(), "Can't build the server\n{}", String::from_utf8_lossy(&output.stderr));
+        target_dir.join("debug").join(format!("server{}", std::env::consts::EXE_SUFFIX))
+    })
+}
+
+impl Server {
+    /// Runs the server with the fixture files and args on top of --listen and --nx-dir
+    pub fn start(args: &[&str]) -> Server {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let nx_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("nx-{}", port));
+        fixtures::sample().write(&nx_dir).unwrap();
+        Server::serve(&nx_dir, args)
+    }
+
+    /// Runs the server with the .nx files already in nx_dir, which is removed when it's dropped
+    pub fn serve(nx_dir: &Path, args: &[&str]) -> Server {
+        let binary = server_binary();
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr = format!("127.0.0.1:{}", port);
+
+        let child = Command::new(binary)
+            .args(["--listen", &addr, "--nx-dir"])
+            .arg(nx_dir)
+            .args(args)
+            .env_remove("NX_SERVER_CONFIG")
+            .env_remove("NX_DIR")
+            .env_remove("NX_TOKENS")
+            .env_remove("NX_CACHE_BYTES")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Can't start the server");
+
+        let start = Instant::now();
+        while TcpStream::connect(&addr).is_err() {
+            assert!(start.elapsed() < Duration::from_secs(10), "Server never started listening on {}", addr);
+            sleep(Duration::from_millis(50));
+        }
+
+        Server {
+            url: format!("ws://{}", addr),
+            nx_dir: nx_dir.to_path_buf(),
+            child,
+        }
+    }
+
+    /// Runs the client at this server with args after --url
+    pub fn client(&self, args: &[&str]) -> Output {
+        client(&self.url, args)
+    }
+
+    /// client's stdout, panicking with its stderr if it failed
+    pub fn stdout(&self, args: &[&str]) -> String {
+        stdout(&self.url, args)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.nx_dir);
+    }
+}
+
+/// Answers the way servers did before Hello- a bare nx::WSRequest (JSON text, or bincode on /wsb)
+/// gets the fixture node, or "ERROR ..." text (a bincode Err on /wsb), one answer per request in order
+///
+/// Anything else, like a protocol::Request, gets the same kind of error, or nothing at all if silent
+pub struct OldServer {
+    pub url: String,
+}
+
+impl OldServer {
+    pub fn start(silent: bool) -> OldServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                std::thread::spawn(move || answer_bare(stream, silent));
+            }
+        });
+        OldServer { url }
+    }
+
+    pub fn client(&self, args: &[&str]) -> Output {
+        client(&self.url, args)
+    }
+
+    pub fn stdout(&self, args: &[&str]) -> String {
+        stdout(&self.url, args)
+    }
+}
+
+fn answer_bare(stream: TcpStream, silent: bool) {
+    let mut socket = match tungstenite::accept(stream) {
+        Ok(socket) => socket,
+        Err(_) => return,
+    };
+    let assets = fixtures::sample();
+    let lookup = |path: &str| match assets.get(path) {
+        Some(node) => Ok(node.to_node_sh()),
+        None => Err(format!("Not found: {}", path)),
+    };
+
+    while let Ok(msg) = socket.read() {
+        let reply = match msg {
+            Message::Text(text) => match serde_json::from_str::<nx::WSRequest>(&text) {
+                Ok(request) => match lookup(&request.path) {
+                    Ok(node) => Message::Text(serde_json::to_string(&node).unwrap()),
+                    Err(e) => Message::Text(format!("ERROR {}", e)),
+                },
+                Err(_) if silent => continue,
+                Err(e) => Message::Text(format!("ERROR Invalid request {:?}, Err {:?}", text, e)),
+            },
+            Message::Binary(bin) => {
+                let result = match bincode::deserialize::<nx::WSRequest>(&bin) {
+                    Ok(request) => lookup(&request.path),
+                    Err(_) if silent => continue,
+                    Err(e) => Err(format!("Invalid request, Err {:?}", e)),
+                };
+                Message::Binary(bincode::serialize(&result).unwrap())
+            }
+            _ => continue,
+        };
+        if socket.send(reply).is_err() {
+            return;
+        }
+    }
+}
+
+/// Runs the client at url with args after --url
+pub fn client(url: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--url", url])
+        .args(args)
+        .env_remove("NX_TOKEN")
+        .output()
+        .expect("Can't run the client")
+}
+
+/// client's stdout, panicking with its stderr if it failed
+pub fn stdout(url: &str, args: &[&str]) -> String {
+    let output = client(url, args);
+    assert!(
+        output.status.success(),
+        "client {:?} failed\n{}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use common::Server;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MAP: &str = "Map.nx/Map/Map1/100000000.img";
+const DEPS: [&str; 4] = [
+    "Map.nx/Back/grassySoil.img",
+    "Map.nx/Obj/login.img",
+    "Map.nx/Tile/woodMarble.img",
+    "Sound.nx/Bgm00.img/GoPicnic",
+];
+
+/// An empty out for a test to mirror into
+fn out(name: &str) -> PathBuf {
+    let out = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&out);
+    out
+}
+
+fn mirror(server: &Server, map: &str, out: &Path) -> String {
+    server.stdout(&["mirror", "--map", map, "--out", out.to_str().unwrap()])
+}
+
+fn manifest(out: &Path) -> Value {
+    serde_json::from_slice(&fs::read(out.join("manifest.json")).unwrap()).unwrap()
+}
+
+#[test]
+fn mirror_writes_every_img_the_map_needs() {
+    let server = Server::start(&[]);
+    let out = out("mirror-fresh");
+
+    assert!(mirror(&server, "100000000", &out).contains("5 fetched, 0 already there, 0 missing"));
+
+    let manifest = manifest(&out);
+    assert_eq!(manifest["maps"], serde_json::json!(["100000000"]));
+    assert_eq!(manifest["complete"], true);
+    assert_eq!(manifest["missing"], serde_json::json!({}));
+    let files = manifest["files"].as_object().unwrap();
+    let mut paths: Vec<&str> = files.keys().map(|x| x.as_str()).collect();
+    paths.sort();
+    let mut expected = [&[MAP], &DEPS[..]].concat();
+    expected.sort();
+    assert_eq!(paths, expected);
+    assert_eq!(files[MAP]["deps"], serde_json::json!(DEPS));
+
+    // Each entry is the node's JSON at the size the manifest says
+    for (path, entry) in files {
+        let bytes = fs::read(out.join(entry["file"].as_str().unwrap())).unwrap();
+        assert_eq!(bytes.len() as u64, entry["bytes"].as_u64().unwrap(), "{}", path);
+        serde_json::from_slice::<nx::NodeSH>(&bytes).unwrap();
+    }
+
+    // And the .nx files serve the same map
+    let mirrored = Server::serve(&out.join("nx"), &[]);
+    assert_eq!(mirrored.stdout(&["deps", "100000000"]), server.stdout(&["deps", "100000000"]));
+    for path in DEPS {
+        assert_eq!(mirrored.stdout(&["get", path]), server.stdout(&["get", path]), "{}", path);
+    }
+}
+
+#[test]
+fn mirror_picks_up_where_it_stopped() {
+    let server = Server::start(&[]);
+    let out = out("mirror-resume");
+
+    mirror(&server, "100000000", &out);
+    assert!(mirror(&server, "100000000", &out).contains("0 fetched, 5 already there"));
+
+    // One cut short like a run stopped while writing it, and one that's gone
+    fs::write(out.join("node/Map.nx/Obj/login.img"), b"{\"da").unwrap();
+    fs::remove_file(out.join("node/Map.nx/Tile/woodMarble.img")).unwrap();
+    assert!(mirror(&server, "100000000", &out).contains("2 fetched, 3 already there"));
+
+    let manifest = manifest(&out);
+    assert_eq!(manifest["complete"], true);
+    let entry = &manifest["files"]["Map.nx/Obj/login.img"];
+    assert_eq!(
+        fs::metadata(out.join("node/Map.nx/Obj/login.img")).unwrap().len(),
+        entry["bytes"].as_u64().unwrap()
+    );
+}
+
+#[test]
+fn unknown_maps_leave_the_manifest_alone() {
+    let server = Server::start(&[]);
+    let out = out("mirror-unknown");
+
+    assert!(!server.client(&["mirror", "--map", "999999999", "--out", out.to_str().unwrap()]).status.success());
+    assert!(!out.join("manifest.json").exists());
+
+    mirror(&server, "100000000", &out);
+    let before = manifest(&out);
+    assert!(!server.client(&["mirror", "--map", "999999999", "--out", out.to_str().unwrap()]).status.success());
+    assert_eq!(manifest(&out), before);
+}
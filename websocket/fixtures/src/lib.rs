@@ -0,0 +1,141 @@
+//! Made up nx data for tests, so nothing needs the real game files
+//!
+//! Build trees out of Node, or describe a map with Map and let Map::add_to fill in every img
+//! file it points at (same layouts protocol::img_dependencies and the browser's
+//! get_img_file_hashmap read). Assets::write puts them in a directory the server can serve, and
+//! Node::to_node_sh gives the tree a client would get back for tests that don't need a server
+//!
+//! Node and Assets are nxwrite's, this only adds what's made up
+pub use nxwrite::{Assets, Data, Node};
+
+use std::collections::BTreeMap;
+
+/// Colour of the bitmaps Assets makes up
+pub const SPRITE_COLOUR: [u8; 4] = [255, 0, 0, 255];
+
+/// A map img file, only the parts that point at other img files
+///
+/// - info/bgm "Bgm00/GoPicnic"
+/// - back/N/bS
+/// - N/info/tS
+/// - N/obj/M/oS
+#[derive(Debug, Clone, Default)]
+pub struct Map {
+    pub id: String,
+    pub bgm: Option<String>,
+    pub backs: Vec<String>,
+    pub layers: BTreeMap<u8, Layer>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Layer {
+    pub tile: Option<String>,
+    pub objs: Vec<String>,
+}
+
+impl Map {
+    pub fn new(id: &str) -> Map {
+        Map {
+            id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// bgm is "{img}/{name}", eg "Bgm00/GoPicnic" for Sound.nx/Bgm00.img/GoPicnic
+    pub fn bgm(mut self, bgm: &str) -> Map {
+        self.bgm = Some(bgm.to_string());
+        self
+    }
+
+    pub fn back(mut self, b_s: &str) -> Map {
+        self.backs.push(b_s.to_string());
+        self
+    }
+
+    pub fn tile(mut self, layer: u8, t_s: &str) -> Map {
+        self.layers.entry(layer).or_default().tile = Some(t_s.to_string());
+        self
+    }
+
+    pub fn obj(mut self, layer: u8, o_s: &str) -> Map {
+        self.layers.entry(layer).or_default().objs.push(o_s.to_string());
+        self
+    }
+
+    /// Where the img file goes in Map.nx, eg Map/Map1/100000000.img
+    pub fn path(&self) -> String {
+        format!("Map/Map{}/{}.img", self.id.chars().next().unwrap_or('0'), self.id)
+    }
+
+    pub fn img(&self) -> Node {
+        let mut img = Node::empty();
+        if let Some(bgm) = &self.bgm {
+            img.insert("info/bgm", Node::string(bgm));
+        }
+        for (i, b_s) in self.backs.iter().enumerate() {
+            img.insert(&format!("back/{}/bS", i), Node::string(b_s));
+        }
+        for (layer, contents) in &self.layers {
+            if let Some(t_s) = &contents.tile {
+                img.insert(&format!("{}/info/tS", layer), Node::string(t_s));
+            }
+            for (i, o_s) in contents.objs.iter().enumerate() {
+                img.insert(&format!("{}/obj/{}/oS", layer, i), Node::string(o_s));
+            }
+        }
+        img
+    }
+
+    /// The map's img file plus a made up img for every back, tile, obj and bgm it uses
+    /// - Map.nx/Back/{bS}.img/back/0, Map.nx/Tile/{tS}.img/bsc/0, Map.nx/Obj/{oS}.img/obj/0- a 2x2 sprite
+    /// - Sound.nx/{img}.img/{name}- a few bytes of audio
+    pub fn add_to<'a>(&self, assets: &'a mut Assets) -> &'a mut Assets {
+        assets.insert(&format!("Map.nx/{}", self.path()), self.img());
+        for b_s in &self.backs {
+            assets.insert(&format!("Map.nx/Back/{}.img/back/0", b_s), Node::sprite(2, 2, SPRITE_COLOUR));
+        }
+        for layer in self.layers.values() {
+            if let Some(t_s) = &layer.tile {
+                assets.insert(&format!("Map.nx/Tile/{}.img/bsc/0", t_s), Node::sprite(2, 2, SPRITE_COLOUR));
+            }
+            for o_s in &layer.objs {
+                assets.insert(&format!("Map.nx/Obj/{}.img/obj/0", o_s), Node::sprite(2, 2, SPRITE_COLOUR));
+            }
+        }
+        if let Some((img, name)) = self.bgm.as_deref().and_then(|x| x.split_once('/')) {
+            assets.insert(&format!("Sound.nx/{}.img/{}", img, name), Node::audio(b"ID3 not really an mp3"));
+        }
+        assets
+    }
+}
+
+/// MPEG 1 layer III frames at 128kbps and 44.1kHz with nothing in them, 1152 samples each
+pub fn mp3(frames: usize) -> Vec<u8> {
+    let mut frame = vec![0u8; 417];
+    frame[..4].copy_from_slice(&[0xff, 0xfb, 0x90, 0x00]);
+    frame.repeat(frames)
+}
+
+/// Map 100000000 with one of everything, plus a 16x16 back that's too big for one 1KiB frame
+/// and some sounds that really are mp3s
+///
+/// Map.nx/Map/Map1/100000000.img
+///   info/bgm -> Sound.nx/Bgm00.img/GoPicnic
+///   back/0/bS -> Map.nx/Back/grassySoil.img
+///   0/info/tS -> Map.nx/Tile/woodMarble.img
+///   0/obj/0/oS -> Map.nx/Obj/login.img
+/// Sound.nx/Bgm00.img/Silence- mp3(10)
+/// Sound.nx/Bgm00.img/Wrapped- mp3(10) behind an 82 byte WZ sound header
+pub fn sample() -> Assets {
+    let mut assets = Assets::new();
+    Map::new("100000000")
+        .bgm("Bgm00/GoPicnic")
+        .back("grassySoil")
+        .tile(0, "woodMarble")
+        .obj(0, "login")
+        .add_to(&mut assets)
+        .insert("Map.nx/Back/grassySoil.img/back/0", Node::sprite(16, 16, SPRITE_COLOUR))
+        .insert("Sound.nx/Bgm00.img/Silence", Node::audio(&mp3(10)))
+        .insert("Sound.nx/Bgm00.img/Wrapped", Node::audio(&[vec![0; 82], mp3(10)].concat()));
+    assets
+}
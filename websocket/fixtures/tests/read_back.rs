@@ -0,0 +1,42 @@
+use fixtures::Map;
+use nx::GenericNode;
+use std::path::Path;
+
+#[test]
+fn nx_reads_back_what_was_written() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("fixtures-read-back");
+    fixtures::sample().write(&dir).unwrap();
+
+    let map_nx = unsafe { nx::File::open(&dir.join("Map.nx")) }.unwrap();
+    let map = map_nx.root().get("Map").and_then(|x| x.get("Map1")).and_then(|x| x.get("100000000.img")).unwrap();
+    let o_s = map.get("0").and_then(|x| x.get("obj")).and_then(|x| x.get("0")).and_then(|x| x.get("oS")).unwrap();
+    assert_eq!(o_s.string(), Some("login"));
+    let t_s = map.get("0").and_then(|x| x.get("info")).and_then(|x| x.get("tS")).unwrap();
+    assert_eq!(t_s.string(), Some("woodMarble"));
+
+    let back = ["Back", "grassySoil.img", "back", "0"].iter().try_fold(map_nx.root(), |node, part| node.get(part)).unwrap();
+    let bitmap = back.bitmap().unwrap();
+    assert_eq!((bitmap.width(), bitmap.height()), (16, 16));
+    assert_eq!(back.get("origin").and_then(|x| x.vector()), Some((8, 16)));
+
+    let sound_nx = unsafe { nx::File::open(&dir.join("Sound.nx")) }.unwrap();
+    let bgm = sound_nx.root().get("Bgm00.img").and_then(|x| x.get("GoPicnic")).unwrap();
+    assert_eq!(bgm.audio().unwrap().data(), b"ID3 not really an mp3");
+    let wrapped = sound_nx.root().get("Bgm00.img").and_then(|x| x.get("Wrapped")).unwrap();
+    assert_eq!(wrapped.audio().unwrap().data(), [vec![0; 82], fixtures::mp3(10)].concat());
+}
+
+#[test]
+fn node_sh_matches_the_tree() {
+    let map = Map::new("100000000").back("a").back("b").obj(0, "c").obj(2, "d").tile(2, "e");
+    let img = map.img().to_node_sh();
+
+    let string = |path: &str| match &path.split('/').try_fold(&img, |node, part| node.children.get(part))?.data {
+        nx::NodeDataPopulated::String(x) => Some(x.clone()),
+        _ => None,
+    };
+    assert_eq!(string("back/1/bS").as_deref(), Some("b"));
+    assert_eq!(string("2/obj/0/oS").as_deref(), Some("d"));
+    assert_eq!(string("2/info/tS").as_deref(), Some("e"));
+    assert_eq!(string("info/bgm"), None);
+}
@@ -0,0 +1,183 @@
+//! Builds .nx files (PKG4, the format nx::File reads) out of trees of Node
+//!
+//! Node::from_node_sh turns what a server sends back into a Node, so anything downloaded can be
+//! written out again as .nx files and served- see client mirror
+mod pkg4;
+
+use nx::{NodeDataPopulated, NodeSH};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Data {
+    None,
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Vector(i32, i32),
+    /// Uncompressed, 4 bytes per pixel
+    Bitmap { width: u16, height: u16, pixels: Vec<u8> },
+    Audio(Vec<u8>),
+}
+
+/// A node and everything under it, children are named by the map they're in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub data: Data,
+    pub children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    pub fn new(data: Data) -> Node {
+        Node {
+            data,
+            children: BTreeMap::new(),
+        }
+    }
+
+    pub fn empty() -> Node {
+        Node::new(Data::None)
+    }
+
+    pub fn integer(value: i64) -> Node {
+        Node::new(Data::Integer(value))
+    }
+
+    pub fn string(value: &str) -> Node {
+        Node::new(Data::String(value.to_string()))
+    }
+
+    pub fn vector(x: i32, y: i32) -> Node {
+        Node::new(Data::Vector(x, y))
+    }
+
+    /// width x height of a single colour
+    pub fn bitmap(width: u16, height: u16, rgba: [u8; 4]) -> Node {
+        Node::new(Data::Bitmap {
+            width,
+            height,
+            pixels: rgba.repeat(width as usize * height as usize),
+        })
+    }
+
+    pub fn audio(bytes: &[u8]) -> Node {
+        Node::new(Data::Audio(bytes.to_vec()))
+    }
+
+    /// Bitmap with an origin child, the way sprites are stored- the origin is at the bottom middle
+    pub fn sprite(width: u16, height: u16, rgba: [u8; 4]) -> Node {
+        Node::bitmap(width, height, rgba).child("origin", Node::vector(width as i32 / 2, height as i32))
+    }
+
+    pub fn child(mut self, name: &str, node: Node) -> Node {
+        self.children.insert(name.to_string(), node);
+        self
+    }
+
+    /// Node at path below this one, eg "back/0/bS"
+    pub fn get(&self, path: &str) -> Option<&Node> {
+        path.split('/')
+            .filter(|x| !x.is_empty())
+            .try_fold(self, |node, part| node.children.get(part))
+    }
+
+    /// Puts node at path below this one, making empty nodes for any parents that are missing
+    pub fn insert(&mut self, path: &str, node: Node) {
+        let mut parts: Vec<&str> = path.split('/').filter(|x| !x.is_empty()).collect();
+        let name = match parts.pop() {
+            Some(name) => name,
+            None => return *self = node,
+        };
+        let mut parent = self;
+        for part in parts {
+            parent = parent.children.entry(part.to_string()).or_insert_with(Node::empty);
+        }
+        parent.children.insert(name.to_string(), node);
+    }
+
+    /// The same tree as the NodeSH the server sends- bitmaps are LZ4 blocks like in a .nx file
+    pub fn to_node_sh(&self) -> NodeSH {
+        NodeSH {
+            data: match &self.data {
+                Data::None => NodeDataPopulated::None,
+                Data::Integer(x) => NodeDataPopulated::Integer(*x),
+                Data::Float(x) => NodeDataPopulated::Float(*x),
+                Data::String(x) => NodeDataPopulated::String(x.clone()),
+                Data::Vector(x, y) => NodeDataPopulated::Vector(*x, *y),
+                Data::Bitmap { width, height, pixels } => NodeDataPopulated::Bitmap {
+                    data: pkg4::lz4_literals(pixels),
+                    width: *width,
+                    height: *height,
+                },
+                Data::Audio(x) => NodeDataPopulated::Audio(x.clone()),
+            },
+            children: self
+                .children
+                .iter()
+                .map(|(name, child)| (name.clone(), child.to_node_sh()))
+                .collect(),
+        }
+    }
+
+    /// Back from what the server sends, Err if a bitmap doesn't decompress
+    pub fn from_node_sh(node: &NodeSH) -> Result<Node, String> {
+        let data = match &node.data {
+            NodeDataPopulated::None => Data::None,
+            NodeDataPopulated::Integer(x) => Data::Integer(*x),
+            NodeDataPopulated::Float(x) => Data::Float(*x),
+            NodeDataPopulated::String(x) => Data::String(x.clone()),
+            NodeDataPopulated::Vector(x, y) => Data::Vector(*x, *y),
+            NodeDataPopulated::Bitmap { width, height, .. } => Data::Bitmap {
+                width: *width,
+                height: *height,
+                pixels: node.data.decompress()?,
+            },
+            NodeDataPopulated::Audio(x) => Data::Audio(x.clone()),
+        };
+        let mut children = BTreeMap::new();
+        for (name, child) in &node.children {
+            children.insert(name.clone(), Node::from_node_sh(child)?);
+        }
+        Ok(Node { data, children })
+    }
+
+    /// This node as the root of a PKG4 .nx file
+    pub fn to_nx(&self) -> Vec<u8> {
+        pkg4::write(self)
+    }
+}
+
+/// A set of .nx files, keyed by file name (eg "Map.nx")
+#[derive(Debug, Clone, Default)]
+pub struct Assets {
+    pub files: BTreeMap<String, Node>,
+}
+
+impl Assets {
+    pub fn new() -> Assets {
+        Assets::default()
+    }
+
+    /// Puts node at a full path, eg "Map.nx/Back/grassySoil.img/back/0"
+    pub fn insert(&mut self, path: &str, node: Node) -> &mut Assets {
+        let (file, rest) = path.split_once('/').unwrap_or((path, ""));
+        self.files.entry(file.to_string()).or_insert_with(Node::empty).insert(rest, node);
+        self
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Node> {
+        let (file, rest) = path.split_once('/').unwrap_or((path, ""));
+        self.files.get(file)?.get(rest)
+    }
+
+    /// Writes every file into dir as {name}, making dir if it isn't there
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        for (name, root) in &self.files {
+            fs::write(dir.join(name), root.to_nx())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+//! Writer for the PKG4 .nx format nx::File reads
+//! https://nxformat.github.io/
+use crate::{Data, Node};
+
+const HEADER_BYTES: usize = 52;
+
+pub fn write(root: &Node) -> Vec<u8> {
+    // Children of a node have to sit next to each other in the node table, breadth first does that
+    let mut order = vec![("", root)];
+    let mut i = 0;
+    while i < order.len() {
+        let node = order[i].1;
+        order.extend(node.children.iter().map(|(name, child)| (name.as_str(), child)));
+        i += 1;
+    }
+
+    let mut strings: Vec<&str> = vec![];
+    let mut bitmaps: Vec<Vec<u8>> = vec![];
+    let mut audios: Vec<&[u8]> = vec![];
+    let mut nodes = vec![];
+    let mut next_child = 1;
+    for (name, node) in &order {
+        let first_child = if node.children.is_empty() { 0 } else { next_child };
+        next_child += node.children.len() as u32;
+
+        let (kind, data): (u16, [u8; 8]) = match &node.data {
+            Data::None => (0, [0; 8]),
+            Data::Integer(x) => (1, x.to_le_bytes()),
+            Data::Float(x) => (2, x.to_le_bytes()),
+            Data::String(x) => (3, pair(string_id(&mut strings, x), 0)),
+            Data::Vector(x, y) => (4, pair(*x as u32, *y as u32)),
+            Data::Bitmap { width, height, pixels } => {
+                bitmaps.push(lz4_literals(pixels));
+                let size = *width as u32 | (*height as u32) << 16;
+                (5, pair(bitmaps.len() as u32 - 1, size))
+            }
+            Data::Audio(x) => {
+                audios.push(x);
+                (6, pair(audios.len() as u32 - 1, x.len() as u32))
+            }
+        };
+
+        nodes.extend(string_id(&mut strings, name).to_le_bytes());
+        nodes.extend(first_child.to_le_bytes());
+        nodes.extend((node.children.len() as u16).to_le_bytes());
+        nodes.extend(kind.to_le_bytes());
+        nodes.extend(data);
+    }
+
+    let mut out = vec![0u8; HEADER_BYTES];
+    let node_table = align(&mut out);
+    out.extend(nodes);
+
+    let string_table = blobs(&mut out, strings.iter().map(|x| {
+        let mut blob = (x.len() as u16).to_le_bytes().to_vec();
+        blob.extend(x.as_bytes());
+        blob
+    }));
+    let bitmap_table = blobs(&mut out, bitmaps.iter().map(|x| {
+        let mut blob = (x.len() as u32).to_le_bytes().to_vec();
+        blob.extend(x);
+        blob
+    }));
+    let audio_table = blobs(&mut out, audios.iter().map(|x| x.to_vec()));
+
+    let mut header = b"PKG4".to_vec();
+    for (count, offset) in [
+        (order.len(), node_table),
+        (strings.len(), string_table),
+        (bitmaps.len(), bitmap_table),
+        (audios.len(), audio_table),
+    ] {
+        header.extend((count as u32).to_le_bytes());
+        header.extend(offset.to_le_bytes());
+    }
+    out[..HEADER_BYTES].copy_from_slice(&header);
+    out
+}
+
+/// An LZ4 block holding data as one run of literals- no compression but any LZ4 decoder reads it
+pub fn lz4_literals(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    if data.len() < 15 {
+        out.push((data.len() as u8) << 4);
+    } else {
+        out.push(0xF0);
+        let mut rest = data.len() - 15;
+        while rest >= 255 {
+            out.push(255);
+            rest -= 255;
+        }
+        out.push(rest as u8);
+    }
+    out.extend(data);
+    out
+}
+
+fn string_id<'a>(strings: &mut Vec<&'a str>, value: &'a str) -> u32 {
+    match strings.iter().position(|x| *x == value) {
+        Some(id) => id as u32,
+        None => {
+            strings.push(value);
+            strings.len() as u32 - 1
+        }
+    }
+}
+
+fn pair(a: u32, b: u32) -> [u8; 8] {
+    let mut out = [0; 8];
+    out[..4].copy_from_slice(&a.to_le_bytes());
+    out[4..].copy_from_slice(&b.to_le_bytes());
+    out
+}
+
+/// Pads out to 8 bytes, returns where the next thing starts
+fn align(out: &mut Vec<u8>) -> u64 {
+    while !out.len().is_multiple_of(8) {
+        out.push(0);
+    }
+    out.len() as u64
+}
+
+/// Writes each blob then a table of their offsets, returns where the table starts
+fn blobs(out: &mut Vec<u8>, blobs: impl Iterator<Item = Vec<u8>>) -> u64 {
+    let offsets: Vec<u64> = blobs
+        .map(|blob| {
+            let offset = align(out);
+            out.extend(blob);
+            offset
+        })
+        .collect();
+    let table = align(out);
+    for offset in offsets {
+        out.extend(offset.to_le_bytes());
+    }
+    table
+}
@@ -0,0 +1,47 @@
+use nx::GenericNode;
+use nxwrite::{Assets, Node};
+use std::path::Path;
+
+fn assets() -> Assets {
+    let mut assets = Assets::new();
+    assets
+        .insert("Test.nx/values/integer", Node::integer(-7))
+        .insert("Test.nx/values/string", Node::string("hello"))
+        .insert("Test.nx/values/vector", Node::vector(3, -4))
+        .insert("Test.nx/sprite", Node::sprite(4, 2, [1, 2, 3, 4]))
+        .insert("Other.nx/sound", Node::audio(b"not really audio"));
+    assets
+}
+
+#[test]
+fn nx_reads_back_every_kind_of_node() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("nxwrite-read-back");
+    assets().write(&dir).unwrap();
+
+    let test_nx = unsafe { nx::File::open(&dir.join("Test.nx")) }.unwrap();
+    let values = test_nx.root().get("values").unwrap();
+    assert_eq!(values.get("integer").and_then(|x| x.integer()), Some(-7));
+    assert_eq!(values.get("string").and_then(|x| x.string()), Some("hello"));
+    assert_eq!(values.get("vector").and_then(|x| x.vector()), Some((3, -4)));
+
+    let sprite = test_nx.root().get("sprite").unwrap();
+    let bitmap = sprite.bitmap().unwrap();
+    assert_eq!((bitmap.width(), bitmap.height()), (4, 2));
+    assert_eq!(sprite.get("origin").and_then(|x| x.vector()), Some((2, 2)));
+
+    let other_nx = unsafe { nx::File::open(&dir.join("Other.nx")) }.unwrap();
+    let sound = other_nx.root().get("sound").unwrap();
+    assert_eq!(sound.audio().unwrap().data(), b"not really audio");
+}
+
+#[test]
+fn node_sh_round_trips() {
+    let assets = assets();
+    let sprite = assets.get("Test.nx/sprite").unwrap();
+    let node_sh = sprite.to_node_sh();
+    assert_eq!(node_sh.data.decompress().unwrap(), [1, 2, 3, 4].repeat(8));
+    assert_eq!(&Node::from_node_sh(&node_sh).unwrap(), sprite);
+
+    let root = &assets.files["Test.nx"];
+    assert_eq!(&Node::from_node_sh(&root.to_node_sh()).unwrap(), root);
+}
@@ -0,0 +1,507 @@
+//! Messages shared between websocket/server, websocket/client and the browser crates
+//!
+//! Every request carries an id picked by the client which the server copies onto the response,
+//! so a client can have many requests in flight and match responses up as they arrive
+//! (responses come back in the order they finish, not the order they were sent)
+//!
+//! JSON on /ws and /ws_deflated, bincode on /wsb
+//! Big JSON responses can be split into Stream frames so the client can show progress
+//!
+//! /room/{id} is separate- no requests, clients send Presence frames and get RoomEvents back
+//!
+//! Clients start with Op::Hello to check they're talking to a server they understand, see check
+use nx::{NodeDataPopulated, NodeSH};
+use serde::ser::SerializeTuple;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::value::RawValue;
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// Ids start at 1- the server answers requests it can't decode with this id
+pub const UNKNOWN_ID: u32 = 0;
+
+/// Goes up whenever a change means an older build can't read what this one sends
+/// Servers from before Hello existed are version 0- they answer it with an InvalidRequest error
+/// 2- Bitmap data is a base64 string in JSON instead of an array of numbers
+pub const VERSION: u32 = 2;
+/// Oldest version this build can still talk to
+pub const MIN_VERSION: u32 = 2;
+
+/// Every Op by name, what a server puts in its Hello
+pub const OPS: [&str; 8] = ["Get", "BundleMap", "Bitmap", "Children", "Tree", "Audio", "Ping", "Hello"];
+
+/// Names for the ways a server can send responses, each only listed in its Hello if that route is on
+/// - "json"- /ws
+/// - "json+deflate"- /ws_deflated
+/// - "bincode"- /wsb
+/// - "chunks"- Stream frames for ?chunk_bytes=N on the JSON routes
+pub const ENCODINGS: [&str; 4] = ["json", "json+deflate", "bincode", "chunks"];
+
+/// Header every HTTP response (websocket upgrades included) carries VERSION in, for clients that
+/// can read headers and would rather check before sending anything
+pub const VERSION_HEADER: &str = "x-nx-protocol";
+/// Same for schema(), as hex
+pub const SCHEMA_HEADER: &str = "x-nx-schema";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub id: u32,
+    pub op: Op,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Op {
+    /// Node at path with its whole subtree
+    Get(nx::WSRequest),
+    /// Map img for a map id (eg "100000000") followed by every img from img_dependencies
+    BundleMap(String),
+    /// Bitmap node at path, decompressed (and encoded/shrunk if asked) on the server
+    Bitmap(BitmapRequest),
+    /// Names and kinds of the node's children, without anything under them
+    Children(String),
+    /// Node at path down to depth levels (0 is just the node), bitmaps and audio left empty
+    Tree(TreeRequest),
+    /// What the audio node at path is and how long it plays- the bytes themselves come from the
+    /// server's /audio/{path}, which an audio element can be pointed at
+    Audio(String),
+    /// Answered straight away with Payload::Pong- browsers can't send ping frames themselves so
+    /// this is how they check the connection is still alive
+    Ping,
+    /// The client's own Hello, answered with the server's
+    Hello(Hello),
+}
+
+impl Op {
+    /// Name as it appears in OPS
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Get(_) => "Get",
+            Op::BundleMap(_) => "BundleMap",
+            Op::Bitmap(_) => "Bitmap",
+            Op::Children(_) => "Children",
+            Op::Tree(_) => "Tree",
+            Op::Audio(_) => "Audio",
+            Op::Ping => "Ping",
+            Op::Hello(_) => "Hello",
+        }
+    }
+}
+
+/// What each side speaks- names are strings rather than enums so a build can read the Hello of a
+/// newer one that knows about ops it doesn't
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+    pub min_version: u32,
+    /// schema() of the build that sent it
+    pub schema: u64,
+    /// Ops the server handles, or the ones the client wants to use
+    pub ops: Vec<String>,
+    /// Out of ENCODINGS, the ones the server can send or the client can read
+    pub encodings: Vec<String>,
+}
+
+impl Hello {
+    /// This build's versions and schema
+    pub fn new(ops: &[&str], encodings: &[&str]) -> Hello {
+        Hello {
+            version: VERSION,
+            min_version: MIN_VERSION,
+            schema: schema(),
+            ops: ops.iter().map(|x| x.to_string()).collect(),
+            encodings: encodings.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    pub fn supports(&self, op: &str) -> bool {
+        self.ops.iter().any(|x| x == op)
+    }
+
+    pub fn encodes(&self, encoding: &str) -> bool {
+        self.encodings.iter().any(|x| x == encoding)
+    }
+}
+
+/// Err if theirs can't talk to this build- the versions don't overlap, or it was built against
+/// nx types that serialize differently (a renamed field or new variant on the nx branch)
+pub fn check(theirs: &Hello) -> Result<(), String> {
+    if theirs.version < MIN_VERSION || theirs.min_version > VERSION {
+        return Err(format!(
+            "Speaks versions {}-{}, this build speaks {}-{}",
+            theirs.min_version, theirs.version, MIN_VERSION, VERSION
+        ));
+    }
+    if theirs.schema != schema() {
+        return Err(format!(
+            "Built against different nx types, schema {:016x} instead of {:016x}",
+            theirs.schema,
+            schema()
+        ));
+    }
+    Ok(())
+}
+
+/// Hash of how the nx types the protocol carries serialize, so builds against different revisions
+/// of the nx branch can tell they don't match instead of failing to read each other's nodes
+pub fn schema() -> u64 {
+    let node = |data| NodeSH {
+        data,
+        children: Default::default(),
+    };
+    // One child each, more would come out in HashMap order which changes from run to run
+    let mut parent = node(NodeDataPopulated::None);
+    parent.children.insert("child".to_string(), node(NodeDataPopulated::Integer(1)));
+    let nodes = vec![
+        parent,
+        node(NodeDataPopulated::Float(0.5)),
+        node(NodeDataPopulated::String("s".to_string())),
+        node(NodeDataPopulated::Vector(1, 2)),
+        node(NodeDataPopulated::Bitmap {
+            data: vec![1],
+            width: 1,
+            height: 1,
+        }),
+        node(NodeDataPopulated::Audio(vec![1])),
+    ];
+    let json = serde_json::to_string(&(nodes, nx::WSRequest { path: "p".to_string() })).unwrap();
+
+    // FNV-1a, the same everywhere unlike std's hasher
+    json.bytes()
+        .fold(0xcbf29ce484222325, |hash, x| (hash ^ x as u64).wrapping_mul(0x100000001b3))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TreeRequest {
+    pub path: String,
+    pub depth: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BitmapRequest {
+    pub path: String,
+    pub format: BitmapFormat,
+    /// Shrink width and height by this factor, eg 2 for half size- 0 and 1 leave it alone
+    pub downscale: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BitmapFormat {
+    /// 4 bytes per pixel, row by row- ready for tex_image_2d with RGBA/UNSIGNED_BYTE
+    Rgba,
+    Png,
+}
+
+/// What a successful request gets back
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Payload {
+    Node(NodeSH),
+    Bitmap(Bitmap),
+    Children(Vec<Child>),
+    Audio(Audio),
+    Pong,
+    Hello(Hello),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Audio {
+    /// Content-Type /audio/{path} is served with, eg "audio/mpeg"
+    pub mime: String,
+    /// None when the server can't tell, eg for Ogg
+    pub duration_ms: Option<u32>,
+    /// Length of what /audio/{path} sends, which can be less than the node's data
+    pub bytes: u32,
+}
+
+/// One entry of Payload::Children
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Child {
+    pub name: String,
+    pub kind: NodeKind,
+    /// How many children it has in turn, 0 for a leaf
+    pub children: u32,
+}
+
+/// Which NodeDataPopulated variant a node holds, without the data
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum NodeKind {
+    None,
+    Integer,
+    Float,
+    String,
+    Vector,
+    Bitmap,
+    Audio,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bitmap {
+    pub format: BitmapFormat,
+    /// Size after downscaling
+    pub width: u16,
+    pub height: u16,
+    /// The node's origin child, downscaled along with the bitmap- (0, 0) if it has none
+    pub origin: (i32, i32),
+    /// base64 in JSON, plain bytes in bincode
+    #[serde(with = "base64_bytes")]
+    pub data: Vec<u8>,
+}
+
+/// Vec<u8> as a base64 string for JSON, a number per byte is around 3.5x the size
+/// Formats that aren't human readable (bincode) keep the bytes as they are
+mod base64_bytes {
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match serializer.is_human_readable() {
+            true => serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(data)),
+            false => serializer.serialize_bytes(data),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        match deserializer.is_human_readable() {
+            true => {
+                let s = String::deserialize(deserializer)?;
+                base64::engine::general_purpose::STANDARD
+                    .decode(s)
+                    .map_err(serde::de::Error::custom)
+            }
+            false => Vec::<u8>::deserialize(deserializer),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub id: u32,
+    /// Path result belongs to- a bundle answers with many paths under one id
+    pub path: String,
+    pub result: Result<Payload, Error>,
+    /// False while more responses for this id are on the way
+    pub done: bool,
+}
+
+/// What a Response too big for one frame is sent as, on JSON connections opened with
+/// ?chunk_bytes=N- Start says how big it is, then each Chunk is the next piece of its JSON in order
+/// (index counts up from 0), joined back together they parse as the Response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Stream {
+    Start { id: u32, path: String, bytes: u32, chunks: u32 },
+    Chunk { id: u32, index: u32, data: String },
+}
+
+/// Why a request failed- branch on the variant, the String is the detail for logs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Error {
+    /// Path is well formed but nothing is there
+    NotFound(String),
+    /// Not File.nx/node/node..., eg "testingasdfsdfsdfdf"
+    MalformedPath(String),
+    /// Path has a . or .. part
+    PathTraversal(String),
+    /// File.nx isn't one the server has loaded
+    FileNotLoaded(String),
+    /// The frame couldn't be read as a request
+    InvalidRequest(String),
+    /// Turned away for going over a connection limit, fine to try again later
+    Refused(String),
+    /// Went wrong on the server, nothing the client can do about it
+    Internal(String),
+}
+
+impl Error {
+    pub fn message(&self) -> &str {
+        match self {
+            Error::NotFound(x)
+            | Error::MalformedPath(x)
+            | Error::PathTraversal(x)
+            | Error::FileNotLoaded(x)
+            | Error::InvalidRequest(x)
+            | Error::Refused(x)
+            | Error::Internal(x) => x,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Error::NotFound(_) => "Not found",
+            Error::MalformedPath(_) => "Malformed path",
+            Error::PathTraversal(_) => "Path traversal",
+            Error::FileNotLoaded(_) => "File not loaded",
+            Error::InvalidRequest(_) => "Invalid request",
+            Error::Refused(_) => "Refused",
+            Error::Internal(_) => "Internal error",
+        };
+        write!(f, "{}: {}", kind, self.message())
+    }
+}
+
+/// Checks path is File.nx/node/node... without looking anything up
+/// Empty parts are skipped, so "/Map.nx//Obj" is the same as "Map.nx/Obj"
+pub fn check_path(path: &str) -> Result<(), Error> {
+    let mut parts = path.split('/').filter(|x| !x.is_empty());
+
+    match parts.next() {
+        None => return Err(Error::MalformedPath("Empty path".to_string())),
+        Some(file) if !file.ends_with(".nx") || file.len() == 3 => {
+            return Err(Error::MalformedPath(format!("{} doesn't start with a .nx file name", path)))
+        }
+        Some(_) => {}
+    }
+
+    for part in path.split('/') {
+        if part == "." || part == ".." {
+            return Err(Error::PathTraversal(format!("{} has a {} part", path, part)));
+        }
+        if part.chars().any(|x| x == '\\' || x.is_control()) {
+            return Err(Error::MalformedPath(format!("{} has a backslash or control character", path)));
+        }
+    }
+    Ok(())
+}
+
+/// What a client sends on /room/{id}, passed on to everyone else in the room as it is
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Presence {
+    pub x: f32,
+    pub y: f32,
+    /// Anything else the others should see, eg "walk"- the server doesn't look at it
+    pub state: String,
+}
+
+/// What a client gets on /room/{id}, as JSON text frames
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RoomEvent {
+    /// First frame after joining- the id everyone else knows this client by and who's already there
+    Welcome { id: u32, members: Vec<Member> },
+    Joined { id: u32 },
+    Left { id: u32 },
+    Presence { id: u32, presence: Presence },
+    /// The frame wasn't a Presence (or was over the connection's limits), nothing was passed on
+    Error(Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Member {
+    pub id: u32,
+    /// None until it sends its first Presence
+    pub presence: Option<Presence>,
+}
+
+/// Borrowing twin of Response, serializes to exactly the same JSON/bincode
+/// Lets the server send a cached node without copying it first
+#[derive(Debug, Serialize)]
+pub struct ResponseRef<'a> {
+    pub id: u32,
+    pub path: &'a str,
+    pub result: Result<PayloadRef<'a>, &'a Error>,
+    pub done: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub enum PayloadRef<'a> {
+    Node(NodeRef<'a>),
+    Bitmap(&'a Bitmap),
+    Children(&'a [Child]),
+    Audio(&'a Audio),
+    Pong,
+    Hello(&'a Hello),
+}
+
+/// How PayloadRef::Node writes its node- the node itself, or one already serialized for the
+/// connection it's going out on which is copied in as is (Json only into JSON, Bincode only into bincode)
+#[derive(Debug)]
+pub enum NodeRef<'a> {
+    Node(&'a NodeSH),
+    Json(&'a RawValue),
+    Bincode(&'a [u8]),
+}
+
+impl Serialize for NodeRef<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            NodeRef::Node(node) => node.serialize(serializer),
+            NodeRef::Json(json) => json.serialize(serializer),
+            // bincode doesn't put a length in front of tuples so the bytes come out as they are
+            NodeRef::Bincode(bytes) => {
+                let mut tuple = serializer.serialize_tuple(bytes.len())?;
+                for byte in bytes.iter() {
+                    tuple.serialize_element(byte)?;
+                }
+                tuple.end()
+            }
+        }
+    }
+}
+
+/// Path of the img file for a map id, eg 100000000 -> Map.nx/Map/Map1/100000000.img
+pub fn map_path(map_id: &str) -> String {
+    format!(
+        "Map.nx/Map/Map{}/{}.img",
+        map_id.chars().next().unwrap_or('0'),
+        map_id
+    )
+}
+
+/// Other img files an img file asks for
+/// - back/N/bS -> Map.nx/Back/{bS}.img
+/// - N/obj/M/oS -> Map.nx/Obj/{oS}.img
+/// - N/info/tS -> Map.nx/Tile/{tS}.img
+/// - info/bgm "Bgm00/GoPicnic" -> Sound.nx/Bgm00.img/GoPicnic
+pub fn img_dependencies(node: &NodeSH) -> Vec<String> {
+    let mut deps: BTreeSet<String> = BTreeSet::new();
+
+    if let Some(back) = node.children.get("back") {
+        for layer in numbered(back) {
+            if let Some(b_s) = string_child(layer, "bS") {
+                if !b_s.is_empty() {
+                    deps.insert(format!("Map.nx/Back/{}.img", b_s));
+                }
+            }
+        }
+    }
+
+    for layer in numbered(node) {
+        if let Some(obj) = layer.children.get("obj") {
+            for o in numbered(obj) {
+                if let Some(o_s) = string_child(o, "oS") {
+                    deps.insert(format!("Map.nx/Obj/{}.img", o_s));
+                }
+            }
+        }
+
+        if let Some(info) = layer.children.get("info") {
+            if let Some(t_s) = string_child(info, "tS") {
+                deps.insert(format!("Map.nx/Tile/{}.img", t_s));
+            }
+        }
+    }
+
+    if let Some(info) = node.children.get("info") {
+        if let Some(bgm) = string_child(info, "bgm") {
+            if let Some((img, name)) = bgm.split_once('/') {
+                deps.insert(format!("Sound.nx/{}.img/{}", img, name));
+            }
+        }
+    }
+
+    deps.into_iter().collect()
+}
+
+/// Children named 0, 1, 2...
+fn numbered(node: &NodeSH) -> impl Iterator<Item = &NodeSH> {
+    node.children
+        .iter()
+        .filter(|(name, _)| name.parse::<u16>().is_ok())
+        .map(|(_, child)| child)
+}
+
+fn string_child<'a>(node: &'a NodeSH, name: &str) -> Option<&'a str> {
+    match node.children.get(name).map(|x| &x.data) {
+        Some(NodeDataPopulated::String(s)) => Some(s),
+        _ => None,
+    }
+}
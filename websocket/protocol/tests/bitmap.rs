@@ -0,0 +1,33 @@
+use protocol::{Bitmap, BitmapFormat};
+
+fn bitmap() -> Bitmap {
+    Bitmap {
+        format: BitmapFormat::Rgba,
+        width: 1,
+        height: 2,
+        origin: (0, 1),
+        data: vec![0, 1, 2, 255, 128, 64, 32, 16],
+    }
+}
+
+#[test]
+fn json_data_is_base64() {
+    let json = serde_json::to_value(bitmap()).unwrap();
+    assert_eq!(json["data"], "AAEC/4BAIBA=");
+
+    let back: Bitmap = serde_json::from_value(json).unwrap();
+    assert_eq!(back.data, bitmap().data);
+
+    let bad = serde_json::json!({"format": "Rgba", "width": 1, "height": 2, "origin": [0, 1], "data": [0, 1]});
+    assert!(serde_json::from_value::<Bitmap>(bad).is_err());
+}
+
+#[test]
+fn bincode_data_is_plain_bytes() {
+    let bytes = bincode::serialize(&bitmap()).unwrap();
+    // Length then the bytes as they are, the same as a plain Vec<u8>
+    assert!(bytes.ends_with(&[8, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 255, 128, 64, 32, 16]));
+
+    let back: Bitmap = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(back.data, bitmap().data);
+}
@@ -0,0 +1,40 @@
+use protocol::{check, Hello, MIN_VERSION, VERSION};
+
+#[test]
+fn versions_have_to_overlap() {
+    let ours = Hello::new(&["Get"], &["json"]);
+    assert_eq!(check(&ours), Ok(()));
+
+    let older = Hello {
+        version: MIN_VERSION - 1,
+        min_version: 0,
+        ..ours.clone()
+    };
+    assert!(check(&older).is_err());
+
+    let newer = Hello {
+        version: VERSION + 2,
+        min_version: VERSION + 1,
+        ..ours.clone()
+    };
+    assert!(check(&newer).is_err());
+
+    // Newer but still able to talk to us
+    let compatible = Hello {
+        version: VERSION + 1,
+        min_version: VERSION,
+        ..ours
+    };
+    assert_eq!(check(&compatible), Ok(()));
+}
+
+#[test]
+fn schema_has_to_match() {
+    let hello = Hello {
+        schema: protocol::schema() ^ 1,
+        ..Hello::new(&[], &[])
+    };
+    assert!(check(&hello).unwrap_err().contains("nx types"));
+    // Same every time, it's compared across builds
+    assert_eq!(protocol::schema(), protocol::schema());
+}
@@ -0,0 +1,35 @@
+use fixtures::Map;
+
+#[test]
+fn finds_every_kind_of_dependency() {
+    let map = Map::new("100000000")
+        .bgm("Bgm00/GoPicnic")
+        .back("grassySoil")
+        .back("")
+        .tile(0, "woodMarble")
+        .obj(0, "login")
+        .obj(3, "houseDW");
+    assert_eq!(
+        protocol::img_dependencies(&map.img().to_node_sh()),
+        [
+            "Map.nx/Back/grassySoil.img",
+            "Map.nx/Obj/houseDW.img",
+            "Map.nx/Obj/login.img",
+            "Map.nx/Tile/woodMarble.img",
+            "Sound.nx/Bgm00.img/GoPicnic",
+        ]
+    );
+}
+
+#[test]
+fn every_dependency_is_in_the_sample_assets() {
+    let assets = fixtures::sample();
+    let path = protocol::map_path("100000000");
+    let map = assets.get(&path).unwrap().to_node_sh();
+
+    let deps = protocol::img_dependencies(&map);
+    assert_eq!(deps.len(), 4);
+    for dep in deps {
+        assert!(assets.get(&dep).is_some(), "{} is missing", dep);
+    }
+}
@@ -0,0 +1,29 @@
+use protocol::{NodeRef, PayloadRef, ResponseRef};
+use serde_json::value::RawValue;
+
+fn response(node: NodeRef<'_>) -> ResponseRef<'_> {
+    ResponseRef {
+        id: 7,
+        path: "Map.nx/Back/grassySoil.img",
+        result: Ok(PayloadRef::Node(node)),
+        done: true,
+    }
+}
+
+#[test]
+fn serialized_nodes_are_copied_in_as_they_are() {
+    let node = fixtures::sample().get("Map.nx/Back/grassySoil.img").unwrap().to_node_sh();
+
+    let json = RawValue::from_string(serde_json::to_string(&node).unwrap()).unwrap();
+    assert_eq!(
+        serde_json::to_string(&response(NodeRef::Json(&json))).unwrap(),
+        serde_json::to_string(&response(NodeRef::Node(&node))).unwrap()
+    );
+
+    let bytes = bincode::serialize(&node).unwrap();
+    let encoded = bincode::serialize(&response(NodeRef::Bincode(&bytes))).unwrap();
+    assert_eq!(encoded, bincode::serialize(&response(NodeRef::Node(&node))).unwrap());
+    // And read back as a plain Response
+    let back: protocol::Response = bincode::deserialize(&encoded).unwrap();
+    assert!(matches!(back.result, Ok(protocol::Payload::Node(_))));
+}
@@ -0,0 +1,153 @@
+use nx::{GenericNode, NodeDataPopulated, NodeSH};
+use protocol::Error;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Every .nx file found in the asset directory, keyed by file name (eg "Map.nx")
+pub struct Assets {
+    pub root: PathBuf,
+    files: HashMap<String, NxFile>,
+}
+
+/// nx::File is a read-only view over the file so it is safe to share between tasks
+struct NxFile(nx::File);
+
+unsafe impl Send for NxFile {}
+unsafe impl Sync for NxFile {}
+
+impl Assets {
+    pub fn load(root: &Path) -> Assets {
+        let mut files = HashMap::new();
+
+        match fs::read_dir(root) {
+            Ok(entries) => {
+                for entry in entries.flatten() {
+                    let path = entry.path();
+                    if path.extension().and_then(|x| x.to_str()) != Some("nx") {
+                        continue;
+                    }
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    match unsafe { nx::File::open(&path) } {
+                        Ok(file) => {
+                            tracing::info!("Loaded {}", name);
+                            files.insert(name, NxFile(file));
+                        }
+                        Err(e) => tracing::warn!("Unable to load {}, Err {:?}", name, e),
+                    }
+                }
+            }
+            Err(e) => tracing::warn!("Unable to read asset dir {:?}, Err {:?}", root, e),
+        }
+
+        Assets {
+            root: root.to_path_buf(),
+            files,
+        }
+    }
+
+    pub fn file_names(&self) -> Vec<&String> {
+        self.files.keys().collect()
+    }
+
+    /// Finds node at path (eg "Map.nx/Obj/login.img") and copies its whole subtree into a NodeSH
+    pub fn lookup(&self, path: &str) -> Result<NodeSH, Error> {
+        self.find(path, |node| populate(node, u16::MAX, true))
+    }
+
+    /// Like lookup but stops depth levels down and leaves bitmap and audio data empty, so only
+    /// the names and small values get copied
+    pub fn lookup_tree(&self, path: &str, depth: u16) -> Result<NodeSH, Error> {
+        self.find(path, |node| populate(node, depth, false))
+    }
+
+    /// Name, kind and child count of every child of the node at path, sorted by name
+    pub fn children(&self, path: &str) -> Result<Vec<protocol::Child>, Error> {
+        self.find(path, |node| {
+            let mut children: Vec<protocol::Child> = node
+                .iter()
+                .map(|child| protocol::Child {
+                    name: child.name().to_string(),
+                    kind: node_kind(&child),
+                    children: child.iter().count() as u32,
+                })
+                .collect();
+            children.sort_by(|a, b| a.name.cmp(&b.name));
+            children
+        })
+    }
+
+    /// Walks to the node at path and hands it to f
+    fn find<T>(&self, path: &str, f: impl FnOnce(nx::Node) -> T) -> Result<T, Error> {
+        protocol::check_path(path)?;
+        let mut parts = path.split('/').filter(|x| !x.is_empty());
+
+        let file_name = match parts.next() {
+            None => return Err(Error::MalformedPath("Empty path".to_string())),
+            Some(f) => f,
+        };
+        let file = match self.files.get(file_name) {
+            None => return Err(Error::FileNotLoaded(format!("File {} is not loaded", file_name))),
+            Some(f) => &f.0,
+        };
+
+        let mut node = file.root();
+        for part in parts {
+            node = match node.get(part) {
+                None => return Err(Error::NotFound(format!("Node {} not found in {}", part, path))),
+                Some(n) => n,
+            };
+        }
+
+        Ok(f(node))
+    }
+}
+
+/// Copies node and depth levels under it, blobs (bitmaps and audio) only if with_blobs is set
+fn populate(node: nx::Node, depth: u16, with_blobs: bool) -> NodeSH {
+    NodeSH {
+        data: node_data(&node, with_blobs),
+        children: match depth {
+            0 => Default::default(),
+            _ => node
+                .iter()
+                .map(|child| (child.name().to_string(), populate(child, depth - 1, with_blobs)))
+                .collect(),
+        },
+    }
+}
+
+fn node_kind(node: &nx::Node) -> protocol::NodeKind {
+    match node.dtype() {
+        nx::Type::Empty => protocol::NodeKind::None,
+        nx::Type::Integer => protocol::NodeKind::Integer,
+        nx::Type::Float => protocol::NodeKind::Float,
+        nx::Type::String => protocol::NodeKind::String,
+        nx::Type::Vector => protocol::NodeKind::Vector,
+        nx::Type::Bitmap => protocol::NodeKind::Bitmap,
+        nx::Type::Audio => protocol::NodeKind::Audio,
+    }
+}
+
+fn node_data(node: &nx::Node, with_blobs: bool) -> NodeDataPopulated {
+    match node.dtype() {
+        nx::Type::Empty => NodeDataPopulated::None,
+        nx::Type::Integer => NodeDataPopulated::Integer(node.integer().unwrap()),
+        nx::Type::Float => NodeDataPopulated::Float(node.float().unwrap()),
+        nx::Type::String => NodeDataPopulated::String(node.string().unwrap().to_string()),
+        nx::Type::Vector => {
+            let (x, y) = node.vector().unwrap();
+            NodeDataPopulated::Vector(x, y)
+        }
+        nx::Type::Bitmap => {
+            let bitmap = node.bitmap().unwrap();
+            NodeDataPopulated::Bitmap {
+                data: if with_blobs { bitmap.raw().to_vec() } else { vec![] },
+                width: bitmap.width(),
+                height: bitmap.height(),
+            }
+        }
+        nx::Type::Audio if !with_blobs => NodeDataPopulated::Audio(vec![]),
+        nx::Type::Audio => NodeDataPopulated::Audio(node.audio().unwrap().data().to_vec()),
+    }
+}
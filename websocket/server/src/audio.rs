@@ -0,0 +1,155 @@
+//! Works out what's in a Sound.nx audio node so it can be served as something a browser can play
+//!
+//! Nodes are MP3 (almost every BGM), WAV or Ogg, either bare or behind the 82 byte header the
+//! WZ files keep in front of the sound- inspect says where the playable bytes start
+use std::time::Duration;
+
+/// Size of the WZ sound header some exporters leave at the start of the node's data
+const WZ_HEADER_BYTES: usize = 82;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sound {
+    pub mime: &'static str,
+    /// Where the playable bytes start in the node's data
+    pub offset: usize,
+    /// None when the format isn't one that's timed here, or the frames couldn't be read
+    pub duration: Option<Duration>,
+}
+
+impl Sound {
+    /// What protocol::Op::Audio answers with for a node with data_len bytes of data
+    pub fn info(&self, data_len: usize) -> protocol::Audio {
+        protocol::Audio {
+            mime: self.mime.to_string(),
+            duration_ms: self.duration.map(|x| x.as_millis() as u32),
+            bytes: data_len.saturating_sub(self.offset) as u32,
+        }
+    }
+}
+
+pub fn inspect(data: &[u8]) -> Sound {
+    for offset in [0, WZ_HEADER_BYTES] {
+        let sound = match data.get(offset..) {
+            Some(bytes) => sniff(bytes),
+            None => None,
+        };
+        if let Some((mime, duration)) = sound {
+            return Sound { mime, offset, duration };
+        }
+    }
+    Sound {
+        mime: "application/octet-stream",
+        offset: 0,
+        duration: None,
+    }
+}
+
+fn sniff(bytes: &[u8]) -> Option<(&'static str, Option<Duration>)> {
+    if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WAVE") {
+        return Some(("audio/wav", wav_duration(bytes)));
+    }
+    if bytes.starts_with(b"OggS") {
+        return Some(("audio/ogg", None));
+    }
+    if bytes.starts_with(b"ID3") || frame(bytes).is_some() {
+        return Some(("audio/mpeg", mp3_duration(bytes)));
+    }
+    None
+}
+
+/// data chunk size over the fmt chunk's byte rate
+fn wav_duration(bytes: &[u8]) -> Option<Duration> {
+    let mut byte_rate = None;
+    let mut at = 12;
+    while let Some(header) = bytes.get(at..at + 8) {
+        let size = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        match &header[..4] {
+            b"fmt " => byte_rate = bytes.get(at + 16..at + 20).map(|x| u32::from_le_bytes(x.try_into().unwrap())),
+            b"data" => {
+                let byte_rate = byte_rate.filter(|x| *x > 0)?;
+                return Some(Duration::from_secs_f64(size as f64 / byte_rate as f64));
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even size
+        at += 8 + size + size % 2;
+    }
+    None
+}
+
+/// Adds up every frame after the ID3 tag, stopping at the first thing that isn't one
+fn mp3_duration(bytes: &[u8]) -> Option<Duration> {
+    let mut at = 0;
+    if bytes.starts_with(b"ID3") {
+        let header = bytes.get(..10)?;
+        let size = header[6..10].iter().fold(0usize, |size, x| size << 7 | (*x & 0x7f) as usize);
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        at = 10 + size + footer;
+    }
+
+    let mut seconds = 0.0;
+    let mut frames = 0;
+    while let Some(frame) = bytes.get(at..).and_then(frame) {
+        seconds += frame.samples as f64 / frame.sample_rate as f64;
+        frames += 1;
+        at += frame.bytes;
+    }
+    match frames {
+        0 => None,
+        _ => Some(Duration::from_secs_f64(seconds)),
+    }
+}
+
+struct Frame {
+    bytes: usize,
+    samples: u32,
+    sample_rate: u32,
+}
+
+/// MPEG audio frame header at the start of bytes
+fn frame(bytes: &[u8]) -> Option<Frame> {
+    let header = u32::from_be_bytes(bytes.get(..4)?.try_into().unwrap());
+    if header >> 21 != 0x7ff {
+        return None;
+    }
+    // 0 MPEG 2.5, 2 MPEG 2, 3 MPEG 1
+    let version = (header >> 19) & 3;
+    // 1 layer III, 2 layer II, 3 layer I
+    let layer = (header >> 17) & 3;
+    let bitrate_index = ((header >> 12) & 0xf) as usize;
+    let sample_rate_index = ((header >> 10) & 3) as usize;
+    let padding = ((header >> 9) & 1) as usize;
+    if version == 1 || layer == 0 || bitrate_index == 0 || bitrate_index == 15 || sample_rate_index == 3 {
+        return None;
+    }
+
+    let bitrates: [u32; 15] = match (version, layer) {
+        (3, 3) => [0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448],
+        (3, 2) => [0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384],
+        (3, _) => [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320],
+        (_, 3) => [0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256],
+        _ => [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+    };
+    let bitrate = bitrates[bitrate_index] as usize * 1000;
+    let sample_rate = [44100, 48000, 32000][sample_rate_index]
+        >> match version {
+            3 => 0,
+            2 => 1,
+            _ => 2,
+        };
+    let samples = match (version, layer) {
+        (_, 3) => 384,
+        (3, _) | (_, 2) => 1152,
+        _ => 576,
+    };
+
+    let bytes = match layer {
+        3 => (12 * bitrate / sample_rate as usize + padding) * 4,
+        _ => samples as usize / 8 * bitrate / sample_rate as usize + padding,
+    };
+    Some(Frame {
+        bytes,
+        samples,
+        sample_rate,
+    })
+}
@@ -0,0 +1,51 @@
+//! Shared secret check done before a route runs (and so before a websocket is upgraded)
+//!
+//! Only layered on when tokens are configured. The token goes in either
+//! - ?token=... on the url, which is all a browser's WebSocket can do
+//! - an Authorization: Bearer ... header
+use crate::AppState;
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::sync::Arc;
+
+pub async fn require_token(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    match token(&request) {
+        Some(token) if state.tokens.0.iter().any(|x| same(x.as_bytes(), token.as_bytes())) => next.run(request).await,
+        Some(_) => {
+            tracing::debug!("Refused {} with a bad token", request.uri().path());
+            (StatusCode::UNAUTHORIZED, "Unknown token").into_response()
+        }
+        None => {
+            tracing::debug!("Refused {} without a token", request.uri().path());
+            (StatusCode::UNAUTHORIZED, "Missing token").into_response()
+        }
+    }
+}
+
+fn token(request: &Request) -> Option<String> {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "));
+    if let Some(token) = bearer {
+        return Some(token.trim().to_string());
+    }
+
+    // Decoded the same way the client encodes it, so + and %xx mean the same thing on both ends
+    let query = request.uri().query()?;
+    form_urlencoded::parse(query.as_bytes()).find_map(|(key, value)| match key == "token" {
+        true => Some(value.into_owned()),
+        false => None,
+    })
+}
+
+/// Compares every byte so how long it takes doesn't give away how much of the token was right
+fn same(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,74 @@
+use nx::{NodeDataPopulated, NodeSH};
+use protocol::{Bitmap, BitmapFormat};
+
+/// Decompresses the bitmap at node, shrinks it by downscale and encodes it as format
+/// CPU heavy so call it from spawn_blocking
+pub fn decode(node: &NodeSH, format: BitmapFormat, downscale: u16) -> Result<Bitmap, String> {
+    let (width, height) = match node.data {
+        NodeDataPopulated::Bitmap { width, height, .. } => (width, height),
+        _ => return Err("Not a bitmap".to_string()),
+    };
+    let origin = match node.children.get("origin").map(|x| &x.data) {
+        Some(NodeDataPopulated::Vector(x, y)) => (*x, *y),
+        _ => (0, 0),
+    };
+
+    let rgba = node.data.decompress()?;
+    let factor = downscale.max(1);
+    let (rgba, width, height) = shrink(rgba, width, height, factor);
+
+    let data = match format {
+        BitmapFormat::Rgba => rgba,
+        BitmapFormat::Png => encode_png(&rgba, width as u32, height as u32)?,
+    };
+
+    Ok(Bitmap {
+        format,
+        width,
+        height,
+        origin: (origin.0 / factor as i32, origin.1 / factor as i32),
+        data,
+    })
+}
+
+/// Averages every factor x factor block of pixels into one
+/// Edge pixels that don't fill a whole block are averaged over the part that's there
+fn shrink(rgba: Vec<u8>, width: u16, height: u16, factor: u16) -> (Vec<u8>, u16, u16) {
+    if factor == 1 {
+        return (rgba, width, height);
+    }
+    let (w, h, f) = (width as usize, height as usize, factor as usize);
+    let new_w = w.div_ceil(f).max(1);
+    let new_h = h.div_ceil(f).max(1);
+
+    let mut out = Vec::with_capacity(new_w * new_h * 4);
+    for y in 0..new_h {
+        for x in 0..new_w {
+            let mut sum = [0u32; 4];
+            let mut count = 0u32;
+            for sy in (y * f)..((y + 1) * f).min(h) {
+                for sx in (x * f)..((x + 1) * f).min(w) {
+                    let i = (sy * w + sx) * 4;
+                    for c in 0..4 {
+                        sum[c] += rgba[i + c] as u32;
+                    }
+                    count += 1;
+                }
+            }
+            out.extend(sum.iter().map(|x| (x / count.max(1)) as u8));
+        }
+    }
+    (out, new_w as u16, new_h as u16)
+}
+
+/// rgba is 4 bytes per pixel, row by row
+pub fn encode_png(rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>, String> {
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(rgba).map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())?;
+    Ok(png)
+}
@@ -0,0 +1,363 @@
+use nx::{NodeDataPopulated, NodeSH};
+use protocol::{Error, NodeRef};
+use serde_json::value::RawValue;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+
+/// What a node is serialized as, one for each kind of websocket route
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Json,
+    Bincode,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Node(String, Encoding),
+    /// An audio node's data, for /audio and Op::Audio
+    Audio(String),
+}
+
+impl Key {
+    pub fn path(&self) -> &str {
+        match self {
+            Key::Node(path, _) | Key::Audio(path) => path,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Node(Arc<Encoded>),
+    Audio(Arc<Audio>),
+}
+
+/// An audio node's data with what audio::inspect made of it, worked out once when it's cached
+/// (timing an MP3 reads every frame header)
+#[derive(Debug)]
+pub struct Audio {
+    pub data: Vec<u8>,
+    pub sound: crate::audio::Sound,
+}
+
+impl Value {
+    /// What key asks for made out of the node it's for
+    /// CPU heavy for big img files so call it from spawn_blocking
+    pub fn new(node: NodeSH, key: &Key) -> Result<Value, Error> {
+        match key {
+            Key::Node(path, encoding) => match Encoded::new(&node, *encoding) {
+                Ok(encoded) => Ok(Value::Node(Arc::new(encoded))),
+                Err(e) => Err(Error::Internal(format!("Unable to serialize {}, Err {}", path, e))),
+            },
+            Key::Audio(path) => match node.data {
+                NodeDataPopulated::Audio(data) => Ok(Value::Audio(Arc::new(Audio {
+                    sound: crate::audio::inspect(&data),
+                    data,
+                }))),
+                _ => Err(Error::NotFound(format!("{} is not audio", path))),
+            },
+        }
+    }
+
+    /// Bytes it takes up, what the budget counts
+    fn size(&self) -> usize {
+        match self {
+            Value::Node(encoded) => encoded.size(),
+            Value::Audio(audio) => audio.data.len(),
+        }
+    }
+}
+
+/// A node serialized once so every request for it after that is a copy
+#[derive(Debug)]
+pub enum Encoded {
+    Json(Box<RawValue>),
+    Bincode(Vec<u8>),
+}
+
+impl Encoded {
+    pub fn new(node: &NodeSH, encoding: Encoding) -> Result<Encoded, String> {
+        match encoding {
+            Encoding::Json => serde_json::to_string(node)
+                .and_then(RawValue::from_string)
+                .map(Encoded::Json)
+                .map_err(|e| format!("{:?}", e)),
+            Encoding::Bincode => bincode::serialize(node).map(Encoded::Bincode).map_err(|e| format!("{:?}", e)),
+        }
+    }
+
+    /// The node back out of it, for the few things that need more than to send it on
+    pub fn node(&self) -> Result<NodeSH, String> {
+        match self {
+            Encoded::Json(json) => serde_json::from_str(json.get()).map_err(|e| format!("{:?}", e)),
+            Encoded::Bincode(bytes) => bincode::deserialize(bytes).map_err(|e| format!("{:?}", e)),
+        }
+    }
+
+    pub fn as_node_ref(&self) -> NodeRef<'_> {
+        match self {
+            Encoded::Json(json) => NodeRef::Json(json),
+            Encoded::Bincode(bytes) => NodeRef::Bincode(bytes),
+        }
+    }
+
+    /// Length of the serialized node
+    pub fn size(&self) -> usize {
+        match self {
+            Encoded::Json(json) => json.get().len(),
+            Encoded::Bincode(bytes) => bytes.len(),
+        }
+    }
+}
+
+/// One lookup of a Key in the nx files, shared by every request for it while it runs
+pub type Lookup = OnceCell<Result<Value, Error>>;
+
+/// Nodes that have already been copied out of the nx files and serialized, keyed by request path
+/// and what they were serialized as
+///
+/// Entries are sized by their serialized length, which is what they take up in memory and what
+/// they cost to send. Once the total goes over budget the least recently used entries are dropped
+pub struct NodeCache {
+    budget: usize,
+    used: usize,
+    entries: HashMap<Key, Entry>,
+    // last_used -> key, oldest first
+    order: BTreeMap<u64, Key>,
+    tick: u64,
+    // Lookups still running, requests for the same key wait on them instead of starting another
+    in_flight: HashMap<Key, Arc<Lookup>>,
+
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+struct Entry {
+    value: Value,
+    size: usize,
+    last_used: u64,
+}
+
+impl NodeCache {
+    pub fn new(budget: usize) -> NodeCache {
+        NodeCache {
+            budget,
+            used: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            in_flight: HashMap::new(),
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &Key) -> Option<Value> {
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.order.remove(&entry.last_used);
+                entry.last_used = self.tick;
+                self.order.insert(self.tick, key.clone());
+                self.hits += 1;
+                Some(entry.value.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Values bigger than the whole budget are not kept
+    pub fn insert(&mut self, key: Key, value: Value) {
+        let size = value.size();
+        if size > self.budget {
+            return;
+        }
+        self.remove(&key);
+
+        while self.used + size > self.budget {
+            match self.order.pop_first() {
+                Some((_, oldest)) => {
+                    if let Some(entry) = self.entries.remove(&oldest) {
+                        self.used -= entry.size;
+                        self.evictions += 1;
+                    }
+                }
+                None => break,
+            }
+        }
+
+        self.tick += 1;
+        self.used += size;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                size,
+                last_used: self.tick,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.last_used);
+            self.used -= entry.size;
+        }
+    }
+
+    /// The lookup already running for key, or a new one for the caller to run
+    pub fn lookup(&mut self, key: &Key) -> Arc<Lookup> {
+        Arc::clone(self.in_flight.entry(key.clone()).or_default())
+    }
+
+    /// Call once key's lookup is done (and in the cache if it worked), later requests for it
+    /// start from the cache again
+    pub fn looked_up(&mut self, key: &Key) {
+        self.in_flight.remove(key);
+    }
+
+    /// Bytes taken up by cached values
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn count(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn hit_ratio(&self) -> f64 {
+        self.hits as f64 / (self.hits + self.misses).max(1) as f64
+    }
+}
+
+impl fmt::Display for NodeCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} entries, {}/{} bytes, {} hits, {} misses ({:.1}% hit), {} evictions",
+            self.entries.len(),
+            self.used,
+            self.budget,
+            self.hits,
+            self.misses,
+            self.hit_ratio() * 100.0,
+            self.evictions
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(path: &str) -> Key {
+        Key::Node(path.to_string(), Encoding::Bincode)
+    }
+
+    /// Stands in for a node serialized to size bytes
+    fn value(size: usize) -> Value {
+        Value::Node(Arc::new(Encoded::Bincode(vec![0; size])))
+    }
+
+    #[test]
+    fn oldest_entries_go_first() {
+        let mut cache = NodeCache::new(30);
+        cache.insert(key("a"), value(10));
+        cache.insert(key("b"), value(10));
+        cache.insert(key("c"), value(10));
+        cache.insert(key("d"), value(10));
+
+        assert!(cache.get(&key("a")).is_none());
+        assert!(cache.get(&key("b")).is_some());
+        assert_eq!((cache.count(), cache.evictions), (3, 1));
+    }
+
+    #[test]
+    fn used_stays_under_budget() {
+        let mut cache = NodeCache::new(25);
+        for (i, size) in [10, 10, 10, 20, 5, 1].into_iter().enumerate() {
+            cache.insert(key(&i.to_string()), value(size));
+            assert!(cache.used() <= 25, "{} used after inserting {}", cache.used(), i);
+        }
+        // The 20 byte entry pushed out the 10s, then the 1 byte one pushed it out
+        assert_eq!((cache.count(), cache.used()), (2, 6));
+
+        // Too big to ever fit, so it doesn't push anything out
+        cache.insert(key("big"), value(26));
+        assert!(cache.get(&key("big")).is_none());
+        assert_eq!((cache.count(), cache.used()), (2, 6));
+
+        // Putting a key in again replaces it instead of counting it twice
+        cache.insert(key("5"), value(4));
+        assert_eq!((cache.count(), cache.used()), (2, 9));
+    }
+
+    #[test]
+    fn hits_count_as_use() {
+        let mut cache = NodeCache::new(30);
+        cache.insert(key("a"), value(10));
+        cache.insert(key("b"), value(10));
+        cache.insert(key("c"), value(10));
+
+        assert!(cache.get(&key("a")).is_some());
+        cache.insert(key("d"), value(10));
+
+        assert!(cache.get(&key("a")).is_some());
+        assert!(cache.get(&key("b")).is_none());
+        assert_eq!((cache.hits, cache.misses), (2, 1));
+    }
+
+    #[test]
+    fn encodings_are_sized_by_what_is_sent() {
+        let node = || fixtures::sample().get("Map.nx/Back/grassySoil.img").unwrap().to_node_sh();
+        let mut cache = NodeCache::new(usize::MAX);
+        for encoding in [Encoding::Json, Encoding::Bincode] {
+            let key = Key::Node("grassySoil".to_string(), encoding);
+            cache.insert(key.clone(), Value::new(node(), &key).unwrap());
+        }
+        let json = serde_json::to_string(&node()).unwrap().len();
+        let bincode = bincode::serialize(&node()).unwrap().len();
+        assert_ne!(json, bincode);
+        assert_eq!((cache.count(), cache.used()), (2, json + bincode));
+
+        let key = Key::Audio("grassySoil".to_string());
+        assert!(matches!(Value::new(node(), &key), Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn audio_is_timed_when_it_is_cached() {
+        let data = [vec![0; 82], fixtures::mp3(10)].concat();
+        let key = Key::Audio("Wrapped".to_string());
+        let audio = match Value::new(fixtures::Node::audio(&data).to_node_sh(), &key).unwrap() {
+            Value::Audio(audio) => audio,
+            value => panic!("Expected audio, got {:?}", value),
+        };
+        assert_eq!(audio.data, data);
+        assert_eq!(audio.sound, crate::audio::inspect(&data));
+        assert_eq!((audio.sound.offset, audio.sound.mime), (82, "audio/mpeg"));
+        assert!(audio.sound.duration.is_some());
+
+        let mut cache = NodeCache::new(usize::MAX);
+        cache.insert(key, Value::Audio(audio));
+        assert_eq!(cache.used(), data.len());
+    }
+
+    #[test]
+    fn lookups_are_shared_until_done() {
+        let mut cache = NodeCache::new(30);
+        let lookup = cache.lookup(&key("a"));
+        assert!(Arc::ptr_eq(&lookup, &cache.lookup(&key("a"))));
+        assert!(!Arc::ptr_eq(&lookup, &cache.lookup(&Key::Audio("a".to_string()))));
+
+        cache.looked_up(&key("a"));
+        assert!(!Arc::ptr_eq(&lookup, &cache.lookup(&key("a"))));
+    }
+}
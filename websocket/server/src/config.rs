@@ -0,0 +1,256 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Every route the server knows about, all enabled unless the config says otherwise
+/// "rest" turns on /node, /children, /bitmap and /audio together, "room" is /room/{id}
+pub const ROUTES: [&str; 7] = ["ws", "wsb", "ws_deflated", "wst", "rest", "room", "metrics"];
+
+#[derive(Parser, Debug)]
+#[command(about = "Serves .nx files over websockets")]
+pub struct Args {
+    /// TOML file with any of the settings below, flags win over the file
+    #[arg(long, env = "NX_SERVER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, eg 127.0.0.1:3001
+    #[arg(long)]
+    pub listen: Option<String>,
+
+    /// Directory scanned for .nx files
+    #[arg(long, env = "NX_DIR")]
+    pub nx_dir: Option<PathBuf>,
+
+    /// Comma separated routes to enable, eg ws,wsb
+    #[arg(long, value_delimiter = ',')]
+    pub routes: Option<Vec<String>>,
+
+    /// trace, debug, info, warn or error
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Comma separated tokens clients must send to connect, none means anyone can
+    #[arg(long, env = "NX_TOKENS", value_delimiter = ',')]
+    pub tokens: Option<Vec<String>>,
+
+    /// Memory budget for cached nodes
+    #[arg(long, env = "NX_CACHE_BYTES")]
+    pub cache_bytes: Option<usize>,
+
+    /// Requests a connection may send per second on average, 0 for no limit
+    #[arg(long)]
+    pub requests_per_second: Option<u32>,
+
+    /// Requests a connection may send at once before the per second limit kicks in
+    #[arg(long)]
+    pub burst: Option<u32>,
+
+    /// Largest frame a client may send, bigger ones close the connection
+    #[arg(long)]
+    pub max_frame_bytes: Option<usize>,
+
+    /// Requests a connection may have running at once, 0 for no limit
+    #[arg(long)]
+    pub max_in_flight: Option<usize>,
+
+    /// Clients a /room/{id} may have at once, 0 for no limit
+    #[arg(long)]
+    pub max_room_members: Option<usize>,
+
+    /// Rooms that may have someone in them at once, 0 for no limit
+    #[arg(long)]
+    pub max_rooms: Option<usize>,
+
+    /// Seconds between pings to each client, 0 to never ping
+    #[arg(long)]
+    pub ping_interval_secs: Option<u64>,
+
+    /// Seconds a client gets to answer a ping before it's disconnected
+    #[arg(long)]
+    pub pong_timeout_secs: Option<u64>,
+
+    /// Seconds without a request before a client is disconnected, 0 to never
+    #[arg(long)]
+    pub idle_timeout_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: String,
+    pub nx_dir: PathBuf,
+    pub routes: Vec<String>,
+    pub log_level: String,
+    pub tokens: Tokens,
+    pub limits: Limits,
+    pub heartbeat: Heartbeat,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Heartbeat {
+    pub ping_interval_secs: u64,
+    pub pong_timeout_secs: u64,
+    pub idle_timeout_secs: u64,
+}
+
+impl Heartbeat {
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval_secs)
+    }
+
+    pub fn pong_timeout(&self) -> Duration {
+        Duration::from_secs(self.pong_timeout_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        // Well under the 60s most proxies (nginx, cloudflare) give a quiet connection
+        Heartbeat {
+            ping_interval_secs: 20,
+            pong_timeout_secs: 10,
+            idle_timeout_secs: 300,
+        }
+    }
+}
+
+/// Kept out of Debug so they don't end up in the logs
+/// Empty ones are dropped wherever they come from, "" would turn auth on and then let ?token= in
+#[derive(Deserialize, Default, Clone)]
+#[serde(from = "Vec<String>")]
+pub struct Tokens(pub Vec<String>);
+
+impl From<Vec<String>> for Tokens {
+    fn from(tokens: Vec<String>) -> Tokens {
+        Tokens(tokens.into_iter().filter(|x| !x.is_empty()).collect())
+    }
+}
+
+impl fmt::Debug for Tokens {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} tokens", self.0.len())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub cache_bytes: usize,
+    pub requests_per_second: u32,
+    pub burst: u32,
+    pub max_frame_bytes: usize,
+    pub max_in_flight: usize,
+    pub max_room_members: usize,
+    pub max_rooms: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: "0.0.0.0:3000".to_string(),
+            nx_dir: PathBuf::from("./nx"),
+            routes: ROUTES.iter().map(|x| x.to_string()).collect(),
+            log_level: "info".to_string(),
+            tokens: Tokens::default(),
+            limits: Limits::default(),
+            heartbeat: Heartbeat::default(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            cache_bytes: 256 * 1024 * 1024,
+            // A map bundle is one request, loose img files can be a few dozen at once
+            requests_per_second: 50,
+            burst: 100,
+            max_frame_bytes: 64 * 1024,
+            max_in_flight: 32,
+            max_room_members: 16,
+            max_rooms: 256,
+        }
+    }
+}
+
+impl Config {
+    /// Defaults, then the config file if there is one, then command line flags
+    pub fn load() -> Result<Config, String> {
+        let args = Args::parse();
+
+        let mut config = match &args.config {
+            None => Config::default(),
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| format!("Unable to read {:?}, Err {}", path, e))?;
+                toml::from_str::<Config>(&text)
+                    .map_err(|e| format!("Unable to parse {:?}, Err {}", path, e))?
+            }
+        };
+
+        if let Some(listen) = args.listen {
+            config.listen = listen;
+        }
+        if let Some(nx_dir) = args.nx_dir {
+            config.nx_dir = nx_dir;
+        }
+        if let Some(routes) = args.routes {
+            config.routes = routes;
+        }
+        if let Some(log_level) = args.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(tokens) = args.tokens {
+            config.tokens = Tokens::from(tokens);
+        }
+        if let Some(cache_bytes) = args.cache_bytes {
+            config.limits.cache_bytes = cache_bytes;
+        }
+        if let Some(requests_per_second) = args.requests_per_second {
+            config.limits.requests_per_second = requests_per_second;
+        }
+        if let Some(burst) = args.burst {
+            config.limits.burst = burst;
+        }
+        if let Some(max_frame_bytes) = args.max_frame_bytes {
+            config.limits.max_frame_bytes = max_frame_bytes;
+        }
+        if let Some(max_in_flight) = args.max_in_flight {
+            config.limits.max_in_flight = max_in_flight;
+        }
+        if let Some(max_room_members) = args.max_room_members {
+            config.limits.max_room_members = max_room_members;
+        }
+        if let Some(max_rooms) = args.max_rooms {
+            config.limits.max_rooms = max_rooms;
+        }
+        if let Some(ping_interval_secs) = args.ping_interval_secs {
+            config.heartbeat.ping_interval_secs = ping_interval_secs;
+        }
+        if let Some(pong_timeout_secs) = args.pong_timeout_secs {
+            config.heartbeat.pong_timeout_secs = pong_timeout_secs;
+        }
+        if let Some(idle_timeout_secs) = args.idle_timeout_secs {
+            config.heartbeat.idle_timeout_secs = idle_timeout_secs;
+        }
+
+        for route in &config.routes {
+            if !ROUTES.contains(&route.as_str()) {
+                return Err(format!("Unknown route {}, expected one of {:?}", route, ROUTES));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn route_enabled(&self, route: &str) -> bool {
+        self.routes.iter().any(|x| x == route)
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Notify};
+
+/// Every websocket that is currently open, and the signal that tells them to close
+pub struct Connections {
+    next_id: AtomicU64,
+    live: Mutex<HashMap<u64, Connection>>,
+    seen_routes: Mutex<BTreeMap<&'static str, usize>>,
+    all_closed: Notify,
+    shutdown: watch::Sender<bool>,
+}
+
+pub struct Connection {
+    pub route: &'static str,
+    pub opened: Instant,
+}
+
+/// Removes the connection again when the handler returns, however it returns
+pub struct Live<'a> {
+    connections: &'a Connections,
+    pub id: u64,
+}
+
+impl Connections {
+    pub fn new() -> Connections {
+        Connections {
+            next_id: AtomicU64::new(1),
+            live: Mutex::new(HashMap::new()),
+            seen_routes: Mutex::new(BTreeMap::new()),
+            all_closed: Notify::new(),
+            shutdown: watch::channel(false).0,
+        }
+    }
+
+    pub fn open(&self, route: &'static str) -> Live<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.seen_routes.lock().unwrap().entry(route).or_insert(0);
+        let count = {
+            let mut live = self.live.lock().unwrap();
+            live.insert(
+                id,
+                Connection {
+                    route,
+                    opened: Instant::now(),
+                },
+            );
+            live.len()
+        };
+        tracing::info!("Connection {} opened on /{}, {} live", id, route, count);
+        Live {
+            connections: self,
+            id,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.live.lock().unwrap().len()
+    }
+
+    /// Live connections per route, every route that has had one is listed even at 0
+    pub fn count_by_route(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = self.seen_routes.lock().unwrap().clone();
+        for connection in self.live.lock().unwrap().values() {
+            *counts.entry(connection.route).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Resolves once the server starts shutting down
+    pub async fn closing(&self) {
+        let mut shutdown = self.shutdown.subscribe();
+        let _ = shutdown.wait_for(|x| *x).await;
+    }
+
+    pub fn shut_down(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Waits for every connection to close, gives up after timeout
+    /// Returns how many were still open
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        let _ = tokio::time::timeout(timeout, async {
+            loop {
+                // Register before checking so a close in between isn't missed
+                let closed = self.all_closed.notified();
+                if self.count() == 0 {
+                    break;
+                }
+                closed.await;
+            }
+        })
+        .await;
+        self.count()
+    }
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Connections::new()
+    }
+}
+
+impl Drop for Live<'_> {
+    fn drop(&mut self) {
+        let mut live = self.connections.live.lock().unwrap();
+        if let Some(connection) = live.remove(&self.id) {
+            tracing::info!(
+                "Connection {} on /{} closed after {:?}, {} live",
+                self.id,
+                connection.route,
+                connection.opened.elapsed(),
+                live.len()
+            );
+        }
+        if live.is_empty() {
+            self.connections.all_closed.notify_waiters();
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use crate::config;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// What a connection should do next, from Heartbeat::next
+pub enum Beat {
+    /// Send a ping frame with this payload
+    Ping(Vec<u8>),
+    /// The last ping wasn't answered in time, the client is gone or stuck
+    NoPong,
+    /// No requests for idle_timeout
+    Idle,
+}
+
+/// Pings the client every ping_interval and keeps track of how long since it last answered or
+/// asked for anything
+///
+/// Proxies (and some load balancers) drop websockets that look quiet, a ping frame every so often
+/// keeps them open. Browsers answer pings by themselves so this works for every client
+pub struct Heartbeat {
+    settings: config::Heartbeat,
+    next_ping: Instant,
+    // When the unanswered ping went out
+    waiting_since: Option<Instant>,
+    last_request: Instant,
+    pings: u64,
+}
+
+impl Heartbeat {
+    pub fn new(settings: &config::Heartbeat) -> Heartbeat {
+        let now = Instant::now();
+        Heartbeat {
+            settings: settings.clone(),
+            next_ping: now + settings.ping_interval(),
+            waiting_since: None,
+            last_request: now,
+            pings: 0,
+        }
+    }
+
+    pub fn pong(&mut self) {
+        self.waiting_since = None;
+    }
+
+    pub fn request(&mut self) {
+        self.last_request = Instant::now();
+    }
+
+    /// Waits until the next thing the connection needs to do, never resolves if everything is off
+    /// Cancel safe- nothing changes until it resolves so it can sit in a select! loop
+    pub async fn next(&mut self) -> Beat {
+        let mut deadlines = vec![];
+        if !self.settings.ping_interval().is_zero() {
+            match self.waiting_since {
+                Some(sent) => deadlines.push(sent + self.settings.pong_timeout()),
+                None => deadlines.push(self.next_ping),
+            }
+        }
+        if !self.settings.idle_timeout().is_zero() {
+            deadlines.push(self.last_request + self.settings.idle_timeout());
+        }
+
+        match deadlines.into_iter().min() {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending::<()>().await,
+        }
+
+        let now = Instant::now();
+        if !self.settings.idle_timeout().is_zero() && now >= self.last_request + self.settings.idle_timeout() {
+            return Beat::Idle;
+        }
+        if self.waiting_since.is_some() {
+            return Beat::NoPong;
+        }
+
+        self.pings += 1;
+        self.waiting_since = Some(now);
+        self.next_ping = now + self.settings.ping_interval();
+        Beat::Ping(self.pings.to_le_bytes().to_vec())
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.settings.idle_timeout()
+    }
+}
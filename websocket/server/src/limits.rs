@@ -0,0 +1,153 @@
+use crate::config::Limits;
+use axum::extract::ws::Message;
+use axum::extract::WebSocketUpgrade;
+use std::time::Instant;
+
+/// Has tungstenite turn away frames over max_frame_bytes while reading them, check only sees a
+/// frame once it's been buffered whole
+pub fn upgrade(ws: WebSocketUpgrade, limits: &Limits) -> WebSocketUpgrade {
+    match limits.max_frame_bytes {
+        0 => ws,
+        max => ws.max_message_size(max).max_frame_size(max),
+    }
+}
+
+/// Whether a read error is tungstenite turning away a frame over the sizes upgrade set
+/// axum doesn't expose tungstenite's error type, only its message
+pub fn too_big(e: &axum::Error) -> bool {
+    e.to_string().starts_with("Space limit exceeded")
+}
+
+/// Why a frame wasn't handed to the route
+pub enum Rejection {
+    /// Answered with an error frame, the connection stays open
+    Refused(String),
+    /// The connection gets closed with this code and reason
+    Close(u16, String),
+}
+
+/// Checked by serve for every frame a connection sends
+pub struct ConnectionLimits {
+    limits: Limits,
+    // Token bucket- refills at requests_per_second up to burst, every request takes one
+    tokens: f64,
+    last: Instant,
+}
+
+impl ConnectionLimits {
+    pub fn new(limits: &Limits) -> ConnectionLimits {
+        ConnectionLimits {
+            limits: limits.clone(),
+            tokens: limits.burst.max(1) as f64,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn check(&mut self, msg: &Message, in_flight: usize) -> Result<(), Rejection> {
+        let size = match msg {
+            Message::Text(text) => text.len(),
+            Message::Binary(bin) => bin.len(),
+            // Control frames are answered by tungstenite itself and don't count as requests
+            _ => return Ok(()),
+        };
+
+        if self.limits.max_frame_bytes > 0 && size > self.limits.max_frame_bytes {
+            return Err(Rejection::Close(
+                axum::extract::ws::close_code::SIZE,
+                format!("Frame of {} bytes is over the {} byte limit", size, self.limits.max_frame_bytes),
+            ));
+        }
+
+        if self.limits.max_in_flight > 0 && in_flight >= self.limits.max_in_flight {
+            return Err(Rejection::Refused(format!(
+                "Too many requests in flight, the limit is {}",
+                self.limits.max_in_flight
+            )));
+        }
+
+        if self.limits.requests_per_second > 0 {
+            let now = Instant::now();
+            let refill = now.duration_since(self.last).as_secs_f64() * self.limits.requests_per_second as f64;
+            self.tokens = (self.tokens + refill).min(self.limits.burst.max(1) as f64);
+            self.last = now;
+
+            if self.tokens < 1.0 {
+                return Err(Rejection::Refused(format!(
+                    "Rate limited, the limit is {} requests per second",
+                    self.limits.requests_per_second
+                )));
+            }
+            self.tokens -= 1.0;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limits(requests_per_second: u32, burst: u32) -> ConnectionLimits {
+        ConnectionLimits::new(&Limits {
+            requests_per_second,
+            burst,
+            max_in_flight: 0,
+            ..Limits::default()
+        })
+    }
+
+    /// How many of n requests in a row get through
+    fn allowed(limits: &mut ConnectionLimits, n: usize) -> usize {
+        (0..n)
+            .filter(|_| limits.check(&Message::Text("{}".to_string()), 0).is_ok())
+            .count()
+    }
+
+    #[test]
+    fn burst_goes_through_then_refused() {
+        let mut limits = limits(1, 3);
+        assert_eq!(allowed(&mut limits, 5), 3);
+        assert!(matches!(
+            limits.check(&Message::Text("{}".to_string()), 0),
+            Err(Rejection::Refused(_))
+        ));
+    }
+
+    #[test]
+    fn tokens_refill_at_the_rate() {
+        let mut limits = limits(10, 5);
+        assert_eq!(allowed(&mut limits, 5), 5);
+
+        // 250ms at 10 a second is 2.5 tokens, only whole ones are spent
+        limits.last -= Duration::from_millis(250);
+        assert_eq!(allowed(&mut limits, 5), 2);
+
+        // The half left over carries on to the next refill
+        limits.last -= Duration::from_millis(50);
+        assert_eq!(allowed(&mut limits, 5), 1);
+    }
+
+    #[test]
+    fn refill_stops_at_burst() {
+        let mut limits = limits(10, 5);
+        assert_eq!(allowed(&mut limits, 5), 5);
+
+        limits.last -= Duration::from_secs(60);
+        assert_eq!(allowed(&mut limits, 20), 5);
+    }
+
+    #[test]
+    fn zero_requests_per_second_is_no_limit() {
+        let mut limits = limits(0, 1);
+        assert_eq!(allowed(&mut limits, 1000), 1000);
+    }
+
+    #[test]
+    fn control_frames_are_free() {
+        let mut limits = limits(1, 1);
+        assert_eq!(allowed(&mut limits, 1), 1);
+        assert!(limits.check(&Message::Ping(vec![]), 0).is_ok());
+    }
+}
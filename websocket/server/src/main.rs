@@ -1,12 +1,58 @@
-use axum::extract::ws::{Message, WebSocket};
-use axum::extract::{path, Query};
+mod assets;
+mod audio;
+mod auth;
+mod bitmap;
+mod cache;
+mod config;
+mod connections;
+mod heartbeat;
+mod limits;
+mod metrics;
+mod ops;
+mod rest;
+mod rooms;
+
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
 use axum::{extract::WebSocketUpgrade, response::IntoResponse, routing::get, Router};
-use std::collections::HashMap;
-use std::str::from_utf8;
-use std::sync::OnceLock;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use std::future::Future;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use std::{env, fs};
-use serde::{Serialize, Deserialize};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+/// Shared by every route
+pub struct AppState {
+    pub assets: assets::Assets,
+    pub cache: Mutex<cache::NodeCache>,
+    pub connections: connections::Connections,
+    pub metrics: metrics::Metrics,
+    pub limits: config::Limits,
+    pub heartbeat: config::Heartbeat,
+    pub tokens: config::Tokens,
+    pub rooms: rooms::Rooms,
+    /// What Op::Hello is answered with
+    pub hello: protocol::Hello,
+}
+
+/// How long requests already in flight get to finish once the server is shutting down
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Smallest chunk a connection can ask for, anything under it is rounded up
+const MIN_CHUNK_BYTES: usize = 1024;
+
+/// Query string of the JSON websocket routes
+#[derive(Deserialize)]
+struct Params {
+    /// Split responses bigger than this into protocol::Stream frames, off if not set
+    chunk_bytes: Option<usize>,
+}
 
 #[tokio::main]
 async fn main() {
@@ -14,87 +60,773 @@ async fn main() {
     // println!("Sleeping");
     // thread::sleep(Duration::from_millis(3000));
 
-    let app = Router::new()
-        .route("/ws", get(ws_handler))
-        .route("/wsb", get(ws_handler_binary))
-        .route("/wst", get(ws_handler_test));
-    // .with_state(COMPLETE_HASH_MAP.get());
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let level = config.log_level.parse::<tracing::Level>().unwrap_or_else(|_| {
+        eprintln!("Unknown log level {}, using info", config.log_level);
+        tracing::Level::INFO
+    });
+    tracing_subscriber::fmt().with_max_level(level).init();
+    tracing::debug!("{:?}", config);
+
+    let assets = assets::Assets::load(&config.nx_dir);
+    tracing::info!("Serving {:?} from {:?}", assets.file_names(), assets.root);
+    let state = Arc::new(AppState {
+        assets,
+        cache: Mutex::new(cache::NodeCache::new(config.limits.cache_bytes)),
+        connections: connections::Connections::new(),
+        metrics: metrics::Metrics::default(),
+        limits: config.limits.clone(),
+        heartbeat: config.heartbeat.clone(),
+        tokens: config.tokens.clone(),
+        rooms: rooms::Rooms::new(),
+        hello: hello(&config),
+    });
+
+    let mut app = Router::new();
+    if config.route_enabled("ws") {
+        app = app.route("/ws", get(ws_handler));
+    }
+    if config.route_enabled("wsb") {
+        app = app.route("/wsb", get(ws_handler_binary));
+    }
+    if config.route_enabled("ws_deflated") {
+        app = app.route("/ws_deflated", get(ws_handler_deflated));
+    }
+    if config.route_enabled("wst") {
+        app = app.route("/wst", get(ws_handler_test));
+    }
+    if config.route_enabled("room") {
+        app = app.route("/room/:id", get(ws_handler_room));
+    }
+    if config.route_enabled("rest") {
+        let rest = Router::new()
+            .route("/node/*path", get(rest::node))
+            .route("/children/*path", get(rest::children))
+            .route("/bitmap/*path", get(rest::bitmap))
+            .route("/audio/*path", get(rest::audio))
+            .route_layer(axum::middleware::from_fn_with_state(Arc::clone(&state), rest::track));
+        app = app.merge(rest);
+    }
+    // Everything so far needs a token when there are any, /metrics doesn't
+    if !config.tokens.0.is_empty() && config.routes.iter().any(|x| x != "metrics") {
+        app = app.route_layer(axum::middleware::from_fn_with_state(Arc::clone(&state), auth::require_token));
+    }
+    if config.route_enabled("metrics") {
+        app = app.route("/metrics", get(metrics::handler));
+    }
+    let app = app
+        .layer(axum::middleware::map_response_with_state(Arc::clone(&state), version_headers))
+        .with_state(Arc::clone(&state));
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    tracing::debug!("listening on {}", listener.local_addr().unwrap());
-    println!("p listening on {}", listener.local_addr().unwrap());
+    let listener = match tokio::net::TcpListener::bind(&config.listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("Unable to listen on {}, Err {}", config.listen, e);
+            std::process::exit(1);
+        }
+    };
+    tracing::info!("listening on {} with routes {:?}", listener.local_addr().unwrap(), config.routes);
     // axum::serve(
     //     listener,
     //     app.into_make_service_with_connect_info::<SocketAddr>(),
     // )
     // .await
     // .unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal(Arc::clone(&state)))
+        .await
+        .unwrap();
+
+    // axum stops tracking a connection once it's upgraded so wait for the websockets here
+    let open = state.connections.drain(DRAIN_TIMEOUT * 2).await;
+    if open > 0 {
+        tracing::warn!("Shutting down with {} connections still open", open);
+    }
+    tracing::info!("Shut down");
+}
+
+/// Every op, and the encodings of the routes that are on
+fn hello(config: &config::Config) -> protocol::Hello {
+    let mut encodings = vec![];
+    for (route, encoding) in [("ws", "json"), ("ws_deflated", "json+deflate"), ("wsb", "bincode")] {
+        if config.route_enabled(route) {
+            encodings.push(encoding);
+        }
+    }
+    if config.route_enabled("ws") || config.route_enabled("ws_deflated") {
+        encodings.push("chunks");
+    }
+    protocol::Hello::new(&protocol::OPS, &encodings)
+}
+
+/// protocol::VERSION_HEADER and protocol::SCHEMA_HEADER on every response
+async fn version_headers(
+    State(state): State<Arc<AppState>>,
+    mut response: axum::response::Response,
+) -> axum::response::Response {
+    let headers = response.headers_mut();
+    headers.insert(protocol::VERSION_HEADER, state.hello.version.into());
+    if let Ok(schema) = format!("{:016x}", state.hello.schema).parse() {
+        headers.insert(protocol::SCHEMA_HEADER, schema);
+    }
+    response
+}
+
+/// Resolves on SIGINT (ctrl-c) or SIGTERM, after telling every websocket to close
+async fn shutdown_signal(state: Arc<AppState>) {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::warn!("Unable to listen for ctrl-c, Err {:?}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!("Unable to listen for SIGTERM, Err {:?}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("Shutting down, closing {} connections", state.connections.count());
+    state.connections.shut_down();
 }
 
 
 #[axum::debug_handler]
 async fn ws_handler(
-    // Query(params): Query<nx_hoster::Params>,
+    Query(params): Query<Params>,
+    State(state): State<Arc<AppState>>,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
-    println!("pre handler");
-    ws.on_upgrade(|ws: WebSocket| async {
-        println!("handler");
-        // stream_data(ws, params).await;
-        stream_data(ws).await;
+    tracing::debug!("pre handler");
+    limits::upgrade(ws, &state.limits).on_upgrade(|ws: WebSocket| async {
+        tracing::debug!("handler");
+        stream_data(ws, state, params).await;
     })
 }
 
-async fn stream_data(mut ws: WebSocket) {
-    loop {
-        tokio::time::sleep(Duration::from_millis(500)).await;
+/// Reads JSON protocol::Request frames and answers with JSON protocol::Response frames
+///
+/// A bare nx::WSRequest (no id) is still answered the old way- the NodeSH at that path as JSON,
+/// or a text frame starting with "ERROR" followed by the protocol::Error as text
+///
+/// Connections opened with ?chunk_bytes=N get responses bigger than N as protocol::Stream frames
+async fn stream_data(ws: WebSocket, state: Arc<AppState>, params: Params) {
+    serve(ws, &Arc::clone(&state), "ws", move |msg, tx| {
+        let state = Arc::clone(&state);
+        async move {
+            let text = match msg {
+                Message::Text(text) => text,
+                _ => return,
+            };
+
+            let start = Instant::now();
+            state.metrics.request("ws");
+            let mut responses = json_responses(&state, &text, params.chunk_bytes);
+            while let Some((response, error)) = responses.recv().await {
+                tracing::debug!("Responding to {} with {} bytes in {:?}", text, response.len(), start.elapsed());
+                state.metrics.response("ws", response.len(), start.elapsed(), error);
+                if tx.send(Message::Text(response)).is_err() {
+                    break;
+                }
+            }
+        }
+    },
+    |msg, e| refuse_json(msg, e).map(Message::Text))
+    .await;
+}
+
+/// Text frames stream_data sends for a single request, and whether each is an error
+/// Responses over chunk_bytes go out as protocol::Stream frames instead
+fn json_responses(state: &Arc<AppState>, text: &str, chunk_bytes: Option<usize>) -> mpsc::UnboundedReceiver<(String, bool)> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let chunk_bytes = chunk_bytes.map(|x| x.max(MIN_CHUNK_BYTES));
+
+    match serde_json::from_str::<protocol::Request>(text) {
+        Ok(request) => {
+            let mut responses = ops::start(state, request, cache::Encoding::Json);
+            tokio::spawn(async move {
+                while let Some(response) = responses.recv().await {
+                    let (id, path) = (response.id, response.path.clone());
+                    let (json, error) = match serde_json::to_string(&response.as_response()) {
+                        Ok(json) => (json, response.result.is_err()),
+                        Err(e) => {
+                            let error = ops::Response {
+                                result: Err(protocol::Error::Internal(format!("Unable to serialize, Err {:?}", e))),
+                                ..response
+                            };
+                            (serde_json::to_string(&error.as_response()).unwrap(), true)
+                        }
+                    };
+                    let frames = match chunk_bytes {
+                        Some(chunk_bytes) if json.len() > chunk_bytes => chunk(&json, id, path, chunk_bytes),
+                        _ => vec![json],
+                    };
+                    if frames.into_iter().any(|frame| tx.send((frame, error)).is_err()) {
+                        break;
+                    }
+                }
+            });
+        }
+        Err(_) => {
+            let state = Arc::clone(state);
+            let text = text.to_string();
+            tokio::spawn(
+                async move {
+                    let response = legacy_response(&state, &text).await;
+                    let error = response.starts_with("ERROR");
+                    let _ = tx.send((response, error));
+                }
+                .in_current_span(),
+            );
+        }
     }
+
+    rx
 }
 
+/// json split into a protocol::Stream Start frame followed by Chunk frames of about chunk_bytes
+fn chunk(json: &str, id: u32, path: String, chunk_bytes: usize) -> Vec<String> {
+    let mut pieces = vec![];
+    let mut start = 0;
+    while start < json.len() {
+        let mut end = (start + chunk_bytes).min(json.len());
+        // Chunks are strings so they can't end partway through a character
+        while !json.is_char_boundary(end) {
+            end -= 1;
+        }
+        pieces.push(&json[start..end]);
+        start = end;
+    }
 
+    let mut frames = vec![protocol::Stream::Start {
+        id,
+        path,
+        bytes: json.len() as u32,
+        chunks: pieces.len() as u32,
+    }];
+    frames.extend(pieces.into_iter().enumerate().map(|(index, data)| protocol::Stream::Chunk {
+        id,
+        index: index as u32,
+        data: data.to_string(),
+    }));
+    frames.iter().map(|frame| serde_json::to_string(frame).unwrap()).collect()
+}
+
+/// Just enough of a protocol::Request to answer it without reading the rest
+#[derive(Deserialize)]
+struct RequestId {
+    id: u32,
+}
+
+/// Error text for a JSON request turned away by serve- a protocol::Response if the request had an
+/// id, otherwise the old "ERROR" text
+fn refuse_json(msg: &Message, e: protocol::Error) -> Option<String> {
+    let text = match msg {
+        Message::Text(text) => text,
+        _ => return None,
+    };
+    match serde_json::from_str::<RequestId>(text) {
+        Ok(RequestId { id }) => {
+            let response = ops::Response {
+                id,
+                path: String::new(),
+                result: Err(e),
+                done: true,
+            };
+            serde_json::to_string(&response.as_response()).ok()
+        }
+        Err(_) => Some(format!("ERROR {}", e)),
+    }
+}
+
+async fn legacy_response(state: &Arc<AppState>, text: &str) -> String {
+    match serde_json::from_str::<nx::WSRequest>(text) {
+        Ok(request) => match ops::get_node(state, &request.path, cache::Encoding::Json).await {
+            Ok(node) => match serde_json::to_string(&node.as_node_ref()) {
+                Ok(json) => json,
+                Err(e) => format!("ERROR Internal error: Unable to serialize {}, Err {:?}", request.path, e),
+            },
+            Err(e) => format!("ERROR {}", e),
+        },
+        Err(e) => format!("ERROR {}", protocol::Error::InvalidRequest(format!("{:?}, Err {:?}", text, e))),
+    }
+}
+
+/// Runs handle for every incoming frame on its own task so one slow img file doesn't hold up
+/// the rest- whatever it pushes into the sender is written out as soon as it is ready
+///
+/// Frames over the connection's limits (config::Limits) don't reach handle- too big closes the
+/// connection, too many or too fast gets the error frame refuse makes for it
+///
+/// Pings the client as configured (config::Heartbeat) and closes the connection when it stops
+/// answering or stops sending requests
+///
+/// Returns once the client closes or drops the connection (requests still running are dropped),
+/// or once the server shuts down (requests still running get DRAIN_TIMEOUT to finish, then the
+/// client is sent a close frame)
+async fn serve<F, Fut, R>(ws: WebSocket, state: &Arc<AppState>, route: &'static str, handle: F, refuse: R)
+where
+    F: Fn(Message, mpsc::UnboundedSender<Message>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+    R: Fn(&Message, protocol::Error) -> Option<Message>,
+{
+    serve_channel(ws, state, route, mpsc::unbounded_channel(), handle, refuse).await;
+}
+
+/// serve writing out whatever comes through channel, for handlers that need its sender before the
+/// first frame arrives- anything holding on to a sender past the end of serve should hold a weak one
+async fn serve_channel<F, Fut, R>(
+    ws: WebSocket,
+    state: &Arc<AppState>,
+    route: &'static str,
+    channel: (mpsc::UnboundedSender<Message>, mpsc::UnboundedReceiver<Message>),
+    handle: F,
+    refuse: R,
+) where
+    F: Fn(Message, mpsc::UnboundedSender<Message>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+    R: Fn(&Message, protocol::Error) -> Option<Message>,
+{
+    let connection = state.connections.open(route);
+    let mut limits = limits::ConnectionLimits::new(&state.limits);
+    let mut heartbeat = heartbeat::Heartbeat::new(&state.heartbeat);
+    let (mut sender, mut receiver) = ws.split();
+    let (tx, mut rx) = channel;
+
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if sender.send(msg).await.is_err() {
+                return;
+            }
+        }
+        // Flushes the reply to the client's close frame if there was one
+        let _ = sender.close().await;
+    });
+
+    let mut requests = JoinSet::new();
+    // None when the client went away, otherwise the close frame to send it
+    let close = loop {
+        tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(Ok(Message::Close(frame))) => {
+                    tracing::debug!("Connection {} closed by client {:?}", connection.id, frame);
+                    break None;
+                }
+                Some(Ok(Message::Pong(_))) => heartbeat.pong(),
+                Some(Ok(msg)) => match limits.check(&msg, requests.len()) {
+                    Ok(()) => {
+                        heartbeat.request();
+                        requests.spawn(handle(msg, tx.clone()).instrument(tracing::info_span!(
+                            "request",
+                            route,
+                            connection = connection.id
+                        )));
+                    }
+                    Err(limits::Rejection::Refused(e)) => {
+                        tracing::debug!("Connection {} refused a request, {}", connection.id, e);
+                        state.metrics.refused(route);
+                        if let Some(reply) = refuse(&msg, protocol::Error::Refused(e)) {
+                            let _ = tx.send(reply);
+                        }
+                    }
+                    Err(limits::Rejection::Close(code, reason)) => {
+                        tracing::debug!("Closing connection {}, {}", connection.id, reason);
+                        state.metrics.refused(route);
+                        break Some(CloseFrame { code, reason: reason.into() });
+                    }
+                },
+                Some(Err(e)) if limits::too_big(&e) => {
+                    tracing::debug!("Closing connection {}, {}", connection.id, e);
+                    state.metrics.refused(route);
+                    break Some(CloseFrame {
+                        code: close_code::SIZE,
+                        reason: format!("Frame is over the {} byte limit", state.limits.max_frame_bytes).into(),
+                    });
+                }
+                Some(Err(e)) => {
+                    tracing::debug!("Connection {} dropped, Err {:?}", connection.id, e);
+                    break None;
+                }
+                None => break None,
+            },
+            // Reap finished requests so they don't pile up on long lived connections
+            Some(_) = requests.join_next(), if !requests.is_empty() => {}
+            beat = heartbeat.next() => match beat {
+                heartbeat::Beat::Ping(payload) => {
+                    let _ = tx.send(Message::Ping(payload));
+                }
+                heartbeat::Beat::NoPong => {
+                    tracing::debug!("Connection {} stopped answering pings", connection.id);
+                    break Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "No pong".into(),
+                    });
+                }
+                // Requests still running count as activity
+                heartbeat::Beat::Idle if !requests.is_empty() => heartbeat.request(),
+                heartbeat::Beat::Idle => {
+                    tracing::debug!("Connection {} idle for {:?}", connection.id, heartbeat.idle_timeout());
+                    break Some(CloseFrame {
+                        code: close_code::NORMAL,
+                        reason: "Idle".into(),
+                    });
+                }
+            },
+            _ = state.connections.closing() => {
+                let _ = tokio::time::timeout(DRAIN_TIMEOUT, async {
+                    while requests.join_next().await.is_some() {}
+                })
+                .await;
+                break Some(CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "Server shutting down".into(),
+                });
+            }
+        }
+    };
+
+    let closed_by_server = close.is_some();
+    if let Some(frame) = close {
+        let _ = tx.send(Message::Close(Some(frame)));
+    }
+    requests.abort_all();
+    drop(tx);
+    let _ = writer.await;
+
+    if closed_by_server {
+        // Give the client a moment to answer the close frame so it sees a clean close
+        let _ = tokio::time::timeout(Duration::from_secs(1), async {
+            while let Some(Ok(_)) = receiver.next().await {}
+        })
+        .await;
+    }
+}
 
 #[axum::debug_handler]
 async fn ws_handler_binary(
     // Query(params): Query<nx_hoster::Params>,
+    State(state): State<Arc<AppState>>,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
-    println!("pre handler");
-    ws.on_upgrade(|ws: WebSocket| async {
-        println!("handler");
+    tracing::debug!("pre handler");
+    limits::upgrade(ws, &state.limits).on_upgrade(|ws: WebSocket| async {
+        tracing::debug!("handler");
         // stream_data(ws, params).await;
-        crate::stream_data_binary(ws).await;
+        crate::stream_data_binary(ws, state).await;
     })
 }
 
-async fn stream_data_binary(mut ws: WebSocket) {
-    loop {
-        tokio::time::sleep(Duration::from_millis(500)).await;
+/// Binary twin of stream_data- reads bincode protocol::Request frames and answers with
+/// bincode protocol::Response frames so errors arrive typed instead of as an "ERROR" string
+async fn stream_data_binary(ws: WebSocket, state: Arc<AppState>) {
+    serve(ws, &Arc::clone(&state), "wsb", move |msg, tx| {
+        let state = Arc::clone(&state);
+        async move {
+            let start = Instant::now();
+            if let Message::Binary(_) | Message::Text(_) = msg {
+                state.metrics.request("wsb");
+            }
+            let error = |e: protocol::Error| ops::Response {
+                id: protocol::UNKNOWN_ID,
+                path: String::new(),
+                result: Err(e),
+                done: true,
+            };
+            let mut responses = match msg {
+                Message::Binary(bin_data) => match bincode::deserialize::<protocol::Request>(&bin_data) {
+                    Ok(request) => ops::start(&state, request, cache::Encoding::Bincode),
+                    Err(e) => single(error(protocol::Error::InvalidRequest(format!("Err {:?}", e)))),
+                },
+                Message::Text(text) => single(error(protocol::Error::InvalidRequest(format!(
+                    "Expected a binary frame, got text {:?}",
+                    text
+                )))),
+                _ => return,
+            };
+
+            while let Some(response) = responses.recv().await {
+                let id = response.id;
+                let error = response.result.is_err();
+                let encoded = match bincode::serialize(&response.as_response()) {
+                    Ok(encoded) => encoded,
+                    Err(e) => {
+                        let error = ops::Response {
+                            result: Err(protocol::Error::Internal(format!("Unable to serialize, Err {:?}", e))),
+                            ..response
+                        };
+                        bincode::serialize(&error.as_response()).unwrap()
+                    }
+                };
+                tracing::debug!("Responding to {} with {} bytes in {:?}", id, encoded.len(), start.elapsed());
+                state.metrics.response("wsb", encoded.len(), start.elapsed(), error);
+                if tx.send(Message::Binary(encoded)).is_err() {
+                    break;
+                }
+            }
+        }
+    },
+    refuse_binary)
+    .await;
+}
+
+/// Error frame for a bincode request turned away by serve
+fn refuse_binary(msg: &Message, e: protocol::Error) -> Option<Message> {
+    let id = match msg {
+        Message::Binary(bin_data) => bincode::deserialize::<u32>(bin_data).unwrap_or(protocol::UNKNOWN_ID),
+        _ => protocol::UNKNOWN_ID,
+    };
+    let response = ops::Response {
+        id,
+        path: String::new(),
+        result: Err(e),
+        done: true,
+    };
+    bincode::serialize(&response.as_response()).ok().map(Message::Binary)
+}
+
+fn single(response: ops::Response) -> mpsc::UnboundedReceiver<ops::Response> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let _ = tx.send(response);
+    rx
+}
+
+
+#[axum::debug_handler]
+async fn ws_handler_deflated(
+    Query(params): Query<Params>,
+    State(state): State<Arc<AppState>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    tracing::debug!("pre handler");
+    limits::upgrade(ws, &state.limits).on_upgrade(|ws: WebSocket| async {
+        tracing::debug!("handler");
+        crate::stream_data_deflated(ws, state, params).await;
+    })
+}
+
+/// Same requests and responses as stream_data but every response is deflated (RFC 1951) and sent
+/// as a binary frame- the client inflates it back into the JSON/"ERROR" text stream_data sends
+/// tokio-tungstenite can't negotiate permessage-deflate so this is done per message instead
+async fn stream_data_deflated(ws: WebSocket, state: Arc<AppState>, params: Params) {
+    serve(ws, &Arc::clone(&state), "ws_deflated", move |msg, tx| {
+        let state = Arc::clone(&state);
+        async move {
+            let text = match msg {
+                Message::Text(text) => text,
+                _ => return,
+            };
+
+            let start = Instant::now();
+            state.metrics.request("ws_deflated");
+            let mut responses = json_responses(&state, &text, params.chunk_bytes);
+            while let Some((response, error)) = responses.recv().await {
+                let response_len = response.len();
+                let compressed = match tokio::task::spawn_blocking(move || deflate(response.as_bytes())).await {
+                    Ok(Ok(compressed)) => compressed,
+                    e => {
+                        tracing::warn!("Unable to deflate response to {}, Err {:?}", text, e);
+                        return;
+                    }
+                };
+                tracing::debug!(
+                    "Responding to {} with {} bytes deflated from {} ({:.1}%) in {:?}",
+                    text,
+                    compressed.len(),
+                    response_len,
+                    compressed.len() as f64 / response_len.max(1) as f64 * 100.0,
+                    start.elapsed()
+                );
+                state.metrics.response("ws_deflated", compressed.len(), start.elapsed(), error);
+                if tx.send(Message::Binary(compressed)).is_err() {
+                    break;
+                }
+            }
+        }
+    },
+    |msg, e| refuse_json(msg, e).and_then(|text| deflate(text.as_bytes()).ok()).map(Message::Binary))
+    .await;
+}
+
+fn deflate(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+
+#[axum::debug_handler]
+async fn ws_handler_room(
+    Path(room): Path<String>,
+    State(state): State<Arc<AppState>>,
+    ws: WebSocketUpgrade,
+) -> axum::response::Response {
+    if !rooms::valid_id(&room) {
+        return (StatusCode::BAD_REQUEST, "Room ids are 1-64 letters, digits, - or _").into_response();
     }
+    limits::upgrade(ws, &state.limits).on_upgrade(|ws: WebSocket| async {
+        crate::relay(ws, state, room).await;
+    })
+}
+
+/// Joins the room and passes every protocol::Presence the client sends on to everyone else in it,
+/// as protocol::RoomEvent JSON text frames (see rooms::Rooms)
+///
+/// A full room gets a close frame with code 1013 (try again later) instead of a Welcome
+async fn relay(mut ws: WebSocket, state: Arc<AppState>, room: String) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let member = match state.rooms.join(&room, &tx, &state.limits) {
+        Ok(member) => member,
+        Err(e) => {
+            tracing::debug!("Turned a client away from room {}, {}", room, e);
+            state.metrics.refused("room");
+            let _ = ws
+                .send(Message::Close(Some(CloseFrame {
+                    code: close_code::AGAIN,
+                    reason: e.into(),
+                })))
+                .await;
+            // Wait for the client to answer the close frame
+            let _ = tokio::time::timeout(Duration::from_secs(1), async {
+                while let Some(Ok(_)) = ws.recv().await {}
+            })
+            .await;
+            return;
+        }
+    };
+
+    serve_channel(ws, &Arc::clone(&state), "room", (tx, rx), |msg, tx| {
+        // Published here rather than in the returned future so presences go out in the order
+        // they came in, a spawned task could overtake the one before it
+        let start = Instant::now();
+        let presence = match msg {
+            Message::Text(text) => serde_json::from_str::<protocol::Presence>(&text)
+                .map_err(|e| format!("{:?} isn't a Presence, Err {:?}", text, e)),
+            Message::Binary(_) => Err("Expected a text frame, got binary".to_string()),
+            _ => return std::future::ready(()),
+        };
+        state.metrics.request("room");
+        match presence {
+            Ok(presence) => {
+                let bytes = state.rooms.publish(&member, presence);
+                state.metrics.response("room", bytes, start.elapsed(), false);
+            }
+            Err(e) => {
+                let _ = tx.send(rooms::event(&protocol::RoomEvent::Error(protocol::Error::InvalidRequest(e))));
+                state.metrics.response("room", 0, start.elapsed(), true);
+            }
+        }
+        std::future::ready(())
+    },
+    |_, e| Some(rooms::event(&protocol::RoomEvent::Error(e))))
+    .await;
 }
 
 
 #[axum::debug_handler]
 async fn ws_handler_test(
     // Query(params): Query<nx_hoster::Params>,
+    State(state): State<Arc<AppState>>,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
-    println!("pre handler");
-    ws.on_upgrade(|ws: WebSocket| async {
-        println!("handler");
+    tracing::debug!("pre handler");
+    limits::upgrade(ws, &state.limits).on_upgrade(|ws: WebSocket| async {
+        tracing::debug!("handler");
         // stream_data(ws, params).await;
-        crate::stream_data_test(ws).await;
+        crate::stream_data_test(ws, state).await;
     })
 }
 
-async fn stream_data_test(mut ws: WebSocket) {
-    loop {
-        println!("HIT");
-        let data = "testing";
-        let data = nx::WSRequest{path: "testingasdfsdfsdfdf".parse().unwrap() };
-        let encoded: Vec<u8> = bincode::serialize(&data).unwrap();
-        // ws.send(Message::Text(encoded.iter().map(|x| x.to_string()).collect())).await.unwrap();
-        ws.send(Message::Binary(encoded)).await.unwrap();
-        tokio::time::sleep(Duration::from_millis(500)).await;
+/// Sends a bincode nx::WSRequest every 500ms until the client goes away
+async fn stream_data_test(mut ws: WebSocket, state: Arc<AppState>) {
+    let connection = state.connections.open("wst");
+    let mut heartbeat = heartbeat::Heartbeat::new(&state.heartbeat);
+    let mut interval = tokio::time::interval(Duration::from_millis(500));
+
+    // None when the client went away, otherwise the close frame to send it
+    let close = loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let data = nx::WSRequest{path: "testingasdfsdfsdfdf".parse().unwrap() };
+                let encoded: Vec<u8> = bincode::serialize(&data).unwrap();
+                tracing::trace!("Sending connection {} a {} byte test request", connection.id, encoded.len());
+                if let Err(e) = ws.send(Message::Binary(encoded)).await {
+                    tracing::debug!("Connection {} dropped, Err {:?}", connection.id, e);
+                    return;
+                }
+            }
+            msg = ws.recv() => match msg {
+                Some(Ok(Message::Close(_))) | None => break None,
+                Some(Ok(Message::Pong(_))) => heartbeat.pong(),
+                Some(Ok(_)) => heartbeat.request(),
+                Some(Err(e)) if limits::too_big(&e) => {
+                    tracing::debug!("Closing connection {}, {}", connection.id, e);
+                    state.metrics.refused("wst");
+                    break Some(CloseFrame {
+                        code: close_code::SIZE,
+                        reason: format!("Frame is over the {} byte limit", state.limits.max_frame_bytes).into(),
+                    });
+                }
+                Some(Err(e)) => {
+                    tracing::debug!("Connection {} dropped, Err {:?}", connection.id, e);
+                    return;
+                }
+            },
+            beat = heartbeat.next() => match beat {
+                heartbeat::Beat::Ping(payload) => {
+                    if ws.send(Message::Ping(payload)).await.is_err() {
+                        return;
+                    }
+                }
+                heartbeat::Beat::NoPong => break Some(CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "No pong".into(),
+                }),
+                heartbeat::Beat::Idle => break Some(CloseFrame {
+                    code: close_code::NORMAL,
+                    reason: "Idle".into(),
+                }),
+            },
+            _ = state.connections.closing() => break Some(CloseFrame {
+                code: close_code::AWAY,
+                reason: "Server shutting down".into(),
+            }),
+        }
+    };
+
+    match close {
+        Some(frame) => {
+            let _ = ws.send(Message::Close(Some(frame))).await;
+            // Wait for the client to answer the close frame
+            let _ = tokio::time::timeout(Duration::from_secs(1), async {
+                while let Some(Ok(_)) = ws.recv().await {}
+            })
+            .await;
+        }
+        // Flushes the reply to the client's close frame
+        None => {
+            let _ = SinkExt::close(&mut ws).await;
+        }
     }
 }
@@ -0,0 +1,180 @@
+//! Counters for /metrics, rendered in the Prometheus text format
+//! https://prometheus.io/docs/instrumenting/exposition_formats/
+use crate::AppState;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds in seconds, the last bucket (+Inf) is implied
+const BUCKETS: [f64; 12] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Lookups slower than this are logged and kept in the slowest paths list
+pub const SLOW_LOOKUP: Duration = Duration::from_millis(100);
+/// How many of the slowest lookups to keep
+const SLOWEST_KEPT: usize = 10;
+
+pub async fn handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(&state),
+    )
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    routes: Mutex<BTreeMap<&'static str, RouteMetrics>>,
+    lookups: Mutex<Histogram>,
+    slowest: Mutex<Vec<(String, Duration)>>,
+}
+
+#[derive(Default)]
+struct RouteMetrics {
+    requests: u64,
+    refused: u64,
+    errors: u64,
+    responses: u64,
+    bytes_sent: u64,
+    latency: Histogram,
+}
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        for (count, bound) in self.counts.iter_mut().zip(BUCKETS) {
+            if seconds <= bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let (sep, braced) = match labels {
+            "" => ("", String::new()),
+            _ => (",", format!("{{{}}}", labels)),
+        };
+        for (count, bound) in self.counts.iter().zip(BUCKETS) {
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, self.count);
+        let _ = writeln!(out, "{}_sum{} {}", name, braced, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braced, self.count);
+    }
+}
+
+impl Metrics {
+    pub fn request(&self, route: &'static str) {
+        self.routes.lock().unwrap().entry(route).or_default().requests += 1;
+    }
+
+    /// A frame turned away by limits::ConnectionLimits
+    pub fn refused(&self, route: &'static str) {
+        self.routes.lock().unwrap().entry(route).or_default().refused += 1;
+    }
+
+    /// A frame (or HTTP body) sent back, elapsed since its request came in
+    pub fn response(&self, route: &'static str, bytes: usize, elapsed: Duration, error: bool) {
+        let mut routes = self.routes.lock().unwrap();
+        let metrics = routes.entry(route).or_default();
+        metrics.responses += 1;
+        metrics.bytes_sent += bytes as u64;
+        metrics.latency.observe(elapsed);
+        if error {
+            metrics.errors += 1;
+        }
+    }
+
+    /// A node copied out of the nx files, ie a cache miss
+    pub fn lookup(&self, path: &str, elapsed: Duration) {
+        self.lookups.lock().unwrap().observe(elapsed);
+        if elapsed < SLOW_LOOKUP {
+            return;
+        }
+
+        tracing::warn!("Slow lookup {} took {:?}", path, elapsed);
+        let mut slowest = self.slowest.lock().unwrap();
+        match slowest.iter_mut().find(|(p, _)| p == path) {
+            Some(existing) => existing.1 = existing.1.max(elapsed),
+            None => slowest.push((path.to_string(), elapsed)),
+        }
+        slowest.sort_by_key(|(_, elapsed)| std::cmp::Reverse(*elapsed));
+        slowest.truncate(SLOWEST_KEPT);
+    }
+
+    pub fn render(&self, state: &AppState) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP nx_connections_active Websockets currently open");
+        let _ = writeln!(out, "# TYPE nx_connections_active gauge");
+        for (route, count) in state.connections.count_by_route() {
+            let _ = writeln!(out, "nx_connections_active{{route=\"{}\"}} {}", route, count);
+        }
+
+        let _ = writeln!(out, "# HELP nx_rooms_active Rooms with someone in them");
+        let _ = writeln!(out, "# TYPE nx_rooms_active gauge");
+        let _ = writeln!(out, "nx_rooms_active {}", state.rooms.count());
+
+        let routes = self.routes.lock().unwrap();
+        let counters = [
+            ("nx_requests_total", "Requests received"),
+            ("nx_refused_total", "Frames turned away for going over a limit"),
+            ("nx_responses_total", "Frames or bodies sent back"),
+            ("nx_errors_total", "Responses that were errors"),
+            ("nx_bytes_sent_total", "Bytes of response payload sent"),
+        ];
+        for (i, (name, help)) in counters.iter().enumerate() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (route, metrics) in routes.iter() {
+                let values = [metrics.requests, metrics.refused, metrics.responses, metrics.errors, metrics.bytes_sent];
+                let _ = writeln!(out, "{}{{route=\"{}\"}} {}", name, route, values[i]);
+            }
+        }
+
+        let _ = writeln!(out, "# HELP nx_response_seconds Time from request to each response");
+        let _ = writeln!(out, "# TYPE nx_response_seconds histogram");
+        for (route, metrics) in routes.iter() {
+            metrics.latency.render(&mut out, "nx_response_seconds", &format!("route=\"{}\"", route));
+        }
+        drop(routes);
+
+        let _ = writeln!(out, "# HELP nx_lookup_seconds Time to copy a node out of the nx files");
+        let _ = writeln!(out, "# TYPE nx_lookup_seconds histogram");
+        self.lookups.lock().unwrap().render(&mut out, "nx_lookup_seconds", "");
+
+        let _ = writeln!(out, "# HELP nx_slow_lookup_seconds Slowest lookups over {:?}", SLOW_LOOKUP);
+        let _ = writeln!(out, "# TYPE nx_slow_lookup_seconds gauge");
+        for (path, elapsed) in self.slowest.lock().unwrap().iter() {
+            let path = path.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(out, "nx_slow_lookup_seconds{{path=\"{}\"}} {}", path, elapsed.as_secs_f64());
+        }
+
+        let cache = state.cache.lock().unwrap();
+        let gauges = [
+            ("nx_cache_hits_total", "counter", cache.hits as f64),
+            ("nx_cache_misses_total", "counter", cache.misses as f64),
+            ("nx_cache_evictions_total", "counter", cache.evictions as f64),
+            ("nx_cache_hit_ratio", "gauge", cache.hit_ratio()),
+            ("nx_cache_bytes", "gauge", cache.used() as f64),
+            ("nx_cache_entries", "gauge", cache.count() as f64),
+        ];
+        for (name, kind, value) in gauges {
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        out
+    }
+}
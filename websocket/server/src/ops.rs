@@ -0,0 +1,276 @@
+use crate::cache::{Encoded, Encoding, Key, Value};
+use crate::AppState;
+use nx::NodeSH;
+use protocol::{Error, NodeRef, Op, PayloadRef, Request, ResponseRef};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+/// protocol::Response with the node shared with the cache (already serialized) instead of owned
+pub struct Response {
+    pub id: u32,
+    pub path: String,
+    pub result: Result<Payload, Error>,
+    pub done: bool,
+}
+
+pub enum Payload {
+    Node(Arc<Encoded>),
+    Tree(NodeSH),
+    Bitmap(protocol::Bitmap),
+    Children(Vec<protocol::Child>),
+    Audio(protocol::Audio),
+    Pong,
+    Hello(protocol::Hello),
+}
+
+impl Response {
+    pub fn as_response(&self) -> ResponseRef<'_> {
+        ResponseRef {
+            id: self.id,
+            path: &self.path,
+            result: match &self.result {
+                Ok(Payload::Node(node)) => Ok(PayloadRef::Node(node.as_node_ref())),
+                Ok(Payload::Tree(node)) => Ok(PayloadRef::Node(NodeRef::Node(node))),
+                Ok(Payload::Bitmap(bitmap)) => Ok(PayloadRef::Bitmap(bitmap)),
+                Ok(Payload::Children(children)) => Ok(PayloadRef::Children(children)),
+                Ok(Payload::Audio(audio)) => Ok(PayloadRef::Audio(audio)),
+                Ok(Payload::Pong) => Ok(PayloadRef::Pong),
+                Ok(Payload::Hello(hello)) => Ok(PayloadRef::Hello(hello)),
+                Err(e) => Err(e),
+            },
+            done: self.done,
+        }
+    }
+}
+
+/// Runs request on its own task- every Response it produces comes out of the returned
+/// receiver as soon as it's ready, the last one has done set
+/// Nodes come out already serialized as encoding, which has to be what the responses are sent as
+pub fn start(state: &Arc<AppState>, request: Request, encoding: Encoding) -> mpsc::UnboundedReceiver<Response> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(run(Arc::clone(state), request, encoding, tx).in_current_span());
+    rx
+}
+
+async fn run(state: Arc<AppState>, request: Request, encoding: Encoding, tx: mpsc::UnboundedSender<Response>) {
+    let id = request.id;
+    match request.op {
+        Op::Get(p) => {
+            let result = get_node(&state, &p.path, encoding).await.map(Payload::Node);
+            let _ = tx.send(Response {
+                id,
+                path: p.path,
+                result,
+                done: true,
+            });
+        }
+        Op::BundleMap(map_id) => {
+            let path = protocol::map_path(&map_id);
+            let map = get_node(&state, &path, encoding).await;
+            let deps = match map.as_deref().map(Encoded::node) {
+                Ok(Ok(node)) => protocol::img_dependencies(&node),
+                _ => vec![],
+            };
+
+            let _ = tx.send(Response {
+                id,
+                path,
+                result: map.map(Payload::Node),
+                done: deps.is_empty(),
+            });
+
+            let last = deps.len().saturating_sub(1);
+            for (i, path) in deps.into_iter().enumerate() {
+                let result = get_node(&state, &path, encoding).await.map(Payload::Node);
+                if tx
+                    .send(Response {
+                        id,
+                        path,
+                        result,
+                        done: i == last,
+                    })
+                    .is_err()
+                {
+                    // Connection is gone, no point looking up the rest
+                    break;
+                }
+            }
+        }
+        Op::Children(path) => {
+            let result = get_children(&state, &path).await.map(Payload::Children);
+            let _ = tx.send(Response {
+                id,
+                path,
+                result,
+                done: true,
+            });
+        }
+        Op::Tree(request) => {
+            let result = get_tree(&state, &request.path, request.depth).await.map(Payload::Tree);
+            let _ = tx.send(Response {
+                id,
+                path: request.path,
+                result,
+                done: true,
+            });
+        }
+        Op::Audio(path) => {
+            let result = get_audio(&state, &path)
+                .await
+                .map(|audio| Payload::Audio(audio.sound.info(audio.data.len())));
+            let _ = tx.send(Response {
+                id,
+                path,
+                result,
+                done: true,
+            });
+        }
+        Op::Ping => {
+            let _ = tx.send(Response {
+                id,
+                path: String::new(),
+                result: Ok(Payload::Pong),
+                done: true,
+            });
+        }
+        Op::Hello(hello) => {
+            // Answered either way, it's up to the client what to do about a mismatch
+            match protocol::check(&hello) {
+                Ok(()) => tracing::debug!("Hello from a client on version {}", hello.version),
+                Err(e) => tracing::warn!("Hello from a client that doesn't match, {}", e),
+            }
+            let _ = tx.send(Response {
+                id,
+                path: String::new(),
+                result: Ok(Payload::Hello(state.hello.clone())),
+                done: true,
+            });
+        }
+        Op::Bitmap(request) => {
+            let result = get_bitmap(&state, &request).await.map(Payload::Bitmap);
+            let _ = tx.send(Response {
+                id,
+                path: request.path,
+                result,
+                done: true,
+            });
+        }
+    }
+}
+
+pub async fn get_tree(state: &Arc<AppState>, path: &str, depth: u16) -> Result<NodeSH, Error> {
+    let lookup_path = path.to_string();
+    shallow(state, path, move |assets| assets.lookup_tree(&lookup_path, depth)).await
+}
+
+pub async fn get_children(state: &Arc<AppState>, path: &str) -> Result<Vec<protocol::Child>, Error> {
+    let lookup_path = path.to_string();
+    shallow(state, path, move |assets| assets.children(&lookup_path)).await
+}
+
+/// Runs a lookup that only copies part of a subtree on a blocking thread
+/// Cheap enough that the result isn't cached, it goes straight to the nx files every time
+async fn shallow<T: Send + 'static>(
+    state: &Arc<AppState>,
+    path: &str,
+    lookup: impl FnOnce(&crate::assets::Assets) -> Result<T, Error> + Send + 'static,
+) -> Result<T, Error> {
+    let start = Instant::now();
+    let lookup_state = Arc::clone(state);
+    let result = tokio::task::spawn_blocking(move || lookup(&lookup_state.assets)).await;
+    state.metrics.lookup(path, start.elapsed());
+
+    match result {
+        Ok(result) => result,
+        Err(e) => Err(Error::Internal(format!("Lookup failed, Err {:?}", e))),
+    }
+}
+
+/// Bitmaps aren't cached once decoded, only the node they come from
+pub async fn get_bitmap(state: &Arc<AppState>, request: &protocol::BitmapRequest) -> Result<protocol::Bitmap, Error> {
+    let encoded = get_node(state, &request.path, Encoding::Bincode).await?;
+    decode_bitmap(encoded, &request.path, request.format, request.downscale).await
+}
+
+/// The audio node's data and what audio::inspect made of it, shared with the cache
+pub async fn get_audio(state: &Arc<AppState>, path: &str) -> Result<Arc<crate::cache::Audio>, Error> {
+    match get_cached(state, Key::Audio(path.to_string())).await? {
+        Value::Audio(audio) => Ok(audio),
+        Value::Node(_) => Err(Error::Internal(format!("Cached {} as a node instead of audio", path))),
+    }
+}
+
+/// bitmap::decode on a blocking thread, after reading the node back out of encoded
+async fn decode_bitmap(
+    encoded: Arc<Encoded>,
+    path: &str,
+    format: protocol::BitmapFormat,
+    downscale: u16,
+) -> Result<protocol::Bitmap, Error> {
+    let decode_path = path.to_string();
+    let result = tokio::task::spawn_blocking(move || {
+        let node = encoded
+            .node()
+            .map_err(|e| Error::Internal(format!("Unable to read {} back, Err {}", decode_path, e)))?;
+        if !matches!(node.data, nx::NodeDataPopulated::Bitmap { .. }) {
+            return Err(Error::NotFound(format!("{} is not a bitmap", decode_path)));
+        }
+        crate::bitmap::decode(&node, format, downscale)
+            .map_err(|e| Error::Internal(format!("Unable to decode {}, Err {}", decode_path, e)))
+    })
+    .await;
+    match result {
+        Ok(result) => result,
+        Err(e) => Err(Error::Internal(format!("Unable to decode {}, Err {:?}", path, e))),
+    }
+}
+
+/// The node at path serialized as encoding, from the cache or the nx files
+pub async fn get_node(state: &Arc<AppState>, path: &str, encoding: Encoding) -> Result<Arc<Encoded>, Error> {
+    match get_cached(state, Key::Node(path.to_string(), encoding)).await? {
+        Value::Node(encoded) => Ok(encoded),
+        Value::Audio(_) => Err(Error::Internal(format!("Cached {} as audio instead of a node", path))),
+    }
+}
+
+/// Looks up key in the cache, or makes it out of the nx files on a blocking thread
+/// Copying a whole img file out of the nx file and serializing it can take a while so keep it off
+/// the runtime, and only do it once- requests for a key that's already being looked up wait for it
+#[tracing::instrument(level = "debug", skip(state))]
+async fn get_cached(state: &Arc<AppState>, key: Key) -> Result<Value, Error> {
+    let lookup = {
+        let mut cache = state.cache.lock().unwrap();
+        if let Some(value) = cache.get(&key) {
+            tracing::debug!("Cache hit {:?} - {}", key, cache);
+            return Ok(value);
+        }
+        cache.lookup(&key)
+    };
+
+    let result = lookup.get_or_init(|| async {
+        let start = Instant::now();
+        let lookup_state = Arc::clone(state);
+        let lookup_key = key.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let node = lookup_state.assets.lookup(lookup_key.path())?;
+            Value::new(node, &lookup_key)
+        })
+        .await;
+        state.metrics.lookup(key.path(), start.elapsed());
+
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => Err(Error::Internal(format!("Lookup failed, Err {:?}", e))),
+        };
+        let mut cache = state.cache.lock().unwrap();
+        if let Ok(value) = &result {
+            cache.insert(key.clone(), value.clone());
+        }
+        cache.looked_up(&key);
+        tracing::debug!("Cache miss {:?} - {}", key, cache);
+        result
+    });
+    result.await.clone()
+}
@@ -0,0 +1,201 @@
+//! Plain HTTP versions of the websocket lookups so assets can be poked at with curl or a browser
+//!
+//! - GET /node/{path} -> NodeSH as JSON, ?depth=1 for just the node and its children (no bitmaps)
+//! - GET /children/{path} -> child names as a JSON array
+//! - GET /bitmap/{path}.png -> the bitmap at path as a PNG, ?downscale=2 for half size
+//! - GET /audio/{path} -> the sound at path with its Content-Type, and X-Duration-Ms if it's known,
+//!   Range: bytes=... for part of it
+use crate::cache::{Audio, Encoding};
+use crate::{ops, AppState};
+use axum::body::{Body, HttpBody};
+use axum::extract::{MatchedPath, Path, Query, Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures_util::Stream;
+use protocol::Error;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::Instrument;
+
+/// Size of each piece of a sound /audio sends
+const AUDIO_PIECE_BYTES: usize = 64 * 1024;
+
+/// Counts every REST request in metrics (one route per endpoint) and gives it a span
+pub async fn track(State(state): State<Arc<AppState>>, matched: MatchedPath, request: Request, next: Next) -> Response {
+    let route = match matched.as_str().split('/').nth(1) {
+        Some("node") => "node",
+        Some("children") => "children",
+        Some("bitmap") => "bitmap",
+        Some("audio") => "audio",
+        _ => "rest",
+    };
+    let span = tracing::info_span!("request", route, path = %request.uri().path());
+
+    let start = Instant::now();
+    state.metrics.request(route);
+    let response = next.run(request).instrument(span).await;
+
+    // Streamed bodies (audio) don't know their size, they say it in Content-Length instead
+    let bytes = response
+        .body()
+        .size_hint()
+        .exact()
+        .or_else(|| response.headers().get(header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok())
+        .unwrap_or(0) as usize;
+    let error = !response.status().is_success();
+    state.metrics.response(route, bytes, start.elapsed(), error);
+    response
+}
+
+#[derive(Deserialize)]
+pub struct NodeQuery {
+    depth: Option<u16>,
+}
+
+pub async fn node(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+    Query(query): Query<NodeQuery>,
+) -> Response {
+    let result = match query.depth {
+        Some(depth) => ops::get_tree(&state, &path, depth).await.map(|node| Json(node).into_response()),
+        None => ops::get_node(&state, &path, Encoding::Json)
+            .await
+            .map(|node| Json(node.as_node_ref()).into_response()),
+    };
+    result.unwrap_or_else(error)
+}
+
+pub async fn children(State(state): State<Arc<AppState>>, Path(path): Path<String>) -> Response {
+    // Only the names, no need to copy (and cache) the whole subtree for them
+    match ops::get_children(&state, &path).await {
+        Ok(children) => Json(children.iter().map(|x| &x.name).collect::<Vec<_>>()).into_response(),
+        Err(e) => error(e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BitmapQuery {
+    downscale: Option<u16>,
+}
+
+pub async fn bitmap(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+    Query(query): Query<BitmapQuery>,
+) -> Response {
+    let path = match path.strip_suffix(".png") {
+        Some(path) => path.to_string(),
+        None => return error(Error::NotFound(format!("Bitmap paths end in .png, got {}", path))),
+    };
+
+    let request = protocol::BitmapRequest {
+        path,
+        format: protocol::BitmapFormat::Png,
+        downscale: query.downscale.unwrap_or(1),
+    };
+    match ops::get_bitmap(&state, &request).await {
+        Ok(bitmap) => ([(header::CONTENT_TYPE, "image/png")], bitmap.data).into_response(),
+        Err(e) => error(e),
+    }
+}
+
+/// Streams the sound out of the cached node a piece at a time, only the asked for part if there's
+/// a single Range (browsers ask for bits of long BGMs when seeking)
+pub async fn audio(State(state): State<Arc<AppState>>, Path(path): Path<String>, headers: HeaderMap) -> Response {
+    let audio = match ops::get_audio(&state, &path).await {
+        Ok(audio) => audio,
+        Err(e) => return error(e),
+    };
+    let sound = audio.sound.clone();
+    let len = audio.data.len().saturating_sub(sound.offset);
+    let range = headers.get(header::RANGE).and_then(|x| x.to_str().ok());
+    let (status, range) = match byte_range(range, len) {
+        Ok(Some(range)) => (StatusCode::PARTIAL_CONTENT, range),
+        Ok(None) => (StatusCode::OK, 0..len),
+        Err(()) => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", len))],
+            )
+                .into_response()
+        }
+    };
+
+    let mut response = (
+        status,
+        [
+            (header::CONTENT_TYPE, sound.mime.to_string()),
+            (header::CACHE_CONTROL, "max-age=86400".to_string()),
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+            (header::CONTENT_LENGTH, range.len().to_string()),
+        ],
+        Body::from_stream(pieces(audio, sound.offset + range.start..sound.offset + range.end)),
+    )
+        .into_response();
+    let headers = response.headers_mut();
+    if status == StatusCode::PARTIAL_CONTENT {
+        if let Ok(value) = format!("bytes {}-{}/{}", range.start, range.end - 1, len).parse() {
+            headers.insert(header::CONTENT_RANGE, value);
+        }
+    }
+    if let Some(duration) = sound.duration {
+        headers.insert("x-duration-ms", HeaderValue::from(duration.as_millis() as u64));
+    }
+    response
+}
+
+/// data[range] AUDIO_PIECE_BYTES at a time, the sound stays in the cache instead of being copied whole
+fn pieces(audio: Arc<Audio>, range: Range<usize>) -> impl Stream<Item = Result<Vec<u8>, Infallible>> {
+    let end = range.end;
+    futures_util::stream::iter(
+        range
+            .step_by(AUDIO_PIECE_BYTES)
+            .map(move |start| Ok(audio.data[start..(start + AUDIO_PIECE_BYTES).min(end)].to_vec())),
+    )
+}
+
+/// Range header over len bytes- Ok(None) for no header or one this doesn't handle (several ranges,
+/// not bytes), which get the whole thing, Err for a range that's past the end
+fn byte_range(range: Option<&str>, len: usize) -> Result<Option<Range<usize>>, ()> {
+    let spec = match range.and_then(|x| x.strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let range = match (start.parse::<usize>(), end.parse::<usize>()) {
+        // bytes=-N is the last N bytes
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => len.saturating_sub(suffix)..len,
+        (Ok(start), Err(_)) if end.is_empty() => start..len,
+        (Ok(start), Ok(end)) if start <= end => start..(end + 1).min(len),
+        _ => return Ok(None),
+    };
+    match range.start < len {
+        true => Ok(Some(range)),
+        false => Err(()),
+    }
+}
+
+/// Status code for each protocol::Error, the body is the same text the websockets log
+fn error(e: Error) -> Response {
+    let status = match e {
+        Error::NotFound(_) | Error::FileNotLoaded(_) => StatusCode::NOT_FOUND,
+        Error::MalformedPath(_) | Error::PathTraversal(_) | Error::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        Error::Refused(_) => StatusCode::TOO_MANY_REQUESTS,
+        Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    if status.is_server_error() {
+        tracing::warn!("{} {}", status, e);
+    } else {
+        tracing::debug!("{} {}", status, e);
+    }
+    (status, e.to_string()).into_response()
+}
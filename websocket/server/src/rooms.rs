@@ -0,0 +1,142 @@
+use crate::config;
+use axum::extract::ws::Message;
+use protocol::{Member, Presence, RoomEvent};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use tokio::sync::mpsc::{UnboundedSender, WeakUnboundedSender};
+
+/// Every /room/{id} with someone in it- a room goes away when its last member leaves
+pub struct Rooms {
+    next_id: AtomicU32,
+    rooms: Mutex<HashMap<String, BTreeMap<u32, Joined>>>,
+}
+
+struct Joined {
+    /// Weak so the room doesn't keep the connection's writer running after serve is done with it
+    tx: WeakUnboundedSender<Message>,
+    presence: Option<Presence>,
+}
+
+/// Takes the member back out of its room when the handler returns, however it returns
+pub struct Membership<'a> {
+    rooms: &'a Rooms,
+    pub room: String,
+    pub id: u32,
+}
+
+impl Rooms {
+    pub fn new() -> Rooms {
+        Rooms {
+            next_id: AtomicU32::new(1),
+            rooms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends tx the RoomEvent::Welcome and tells everyone already in the room
+    /// Err if the room has max_room_members already, or it's a new room and there are max_rooms
+    pub fn join(&self, room: &str, tx: &UnboundedSender<Message>, limits: &config::Limits) -> Result<Membership<'_>, String> {
+        let mut rooms = self.rooms.lock().unwrap();
+        if !rooms.contains_key(room) && limits.max_rooms > 0 && rooms.len() >= limits.max_rooms {
+            return Err(format!("Already {} rooms", rooms.len()));
+        }
+        let members = rooms.entry(room.to_string()).or_default();
+        if limits.max_room_members > 0 && members.len() >= limits.max_room_members {
+            return Err(format!("Room {} is full", room));
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let welcome = RoomEvent::Welcome {
+            id,
+            members: members
+                .iter()
+                .map(|(id, member)| Member {
+                    id: *id,
+                    presence: member.presence.clone(),
+                })
+                .collect(),
+        };
+        let _ = tx.send(event(&welcome));
+        broadcast(members, id, &RoomEvent::Joined { id });
+        members.insert(
+            id,
+            Joined {
+                tx: tx.downgrade(),
+                presence: None,
+            },
+        );
+        tracing::info!("Member {} joined room {}, {} in it", id, room, members.len());
+
+        Ok(Membership {
+            rooms: self,
+            room: room.to_string(),
+            id,
+        })
+    }
+
+    /// Remembers presence for members joining later and passes it on to everyone else, returns
+    /// how many bytes that came to
+    pub fn publish(&self, member: &Membership, presence: Presence) -> usize {
+        let mut rooms = self.rooms.lock().unwrap();
+        let members = match rooms.get_mut(&member.room) {
+            Some(members) => members,
+            None => return 0,
+        };
+        let sent = broadcast(
+            members,
+            member.id,
+            &RoomEvent::Presence {
+                id: member.id,
+                presence: presence.clone(),
+            },
+        );
+        if let Some(joined) = members.get_mut(&member.id) {
+            joined.presence = Some(presence);
+        }
+        sent
+    }
+
+    /// Rooms with someone in them
+    pub fn count(&self) -> usize {
+        self.rooms.lock().unwrap().len()
+    }
+
+    fn leave(&self, room: &str, id: u32) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(members) = rooms.get_mut(room) {
+            members.remove(&id);
+            broadcast(members, id, &RoomEvent::Left { id });
+            tracing::info!("Member {} left room {}, {} in it", id, room, members.len());
+            if members.is_empty() {
+                rooms.remove(room);
+            }
+        }
+    }
+}
+
+impl Drop for Membership<'_> {
+    fn drop(&mut self) {
+        self.rooms.leave(&self.room, self.id);
+    }
+}
+
+/// Room ids are 1-64 letters, digits, - or _
+pub fn valid_id(room: &str) -> bool {
+    (1..=64).contains(&room.len()) && room.chars().all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+}
+
+pub fn event(event: &RoomEvent) -> Message {
+    Message::Text(serde_json::to_string(event).unwrap())
+}
+
+/// Sends event to every member but from, returns how many bytes that came to
+fn broadcast(members: &BTreeMap<u32, Joined>, from: u32, event: &RoomEvent) -> usize {
+    let text = serde_json::to_string(event).unwrap();
+    let sent = members
+        .iter()
+        .filter(|(id, _)| **id != from)
+        .filter_map(|(_, member)| member.tx.upgrade())
+        .filter(|tx| tx.send(Message::Text(text.clone())).is_ok())
+        .count();
+    sent * text.len()
+}
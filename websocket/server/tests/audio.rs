@@ -0,0 +1,87 @@
+mod common;
+
+use common::{header, request_json, Server};
+use protocol::{Error, Op, Payload, Request};
+
+#[test]
+fn rest_serves_sounds_with_their_type_and_duration() {
+    let server = Server::start(&[]);
+
+    // 10 frames of 1152 samples at 44.1kHz
+    for path in ["Sound.nx/Bgm00.img/Silence", "Sound.nx/Bgm00.img/Wrapped"] {
+        let (status, headers, body) = server.http_get(&format!("audio/{}", path));
+        assert_eq!(status, 200, "{}", path);
+        assert_eq!(header(&headers, "content-type"), Some("audio/mpeg"));
+        assert_eq!(header(&headers, "x-duration-ms"), Some("261"));
+        // The WZ header isn't part of what's sent
+        assert_eq!(body, fixtures::mp3(10), "{}", path);
+    }
+
+    let (status, _, _) = server.http_get("audio/Map.nx/Obj/login.img/obj/0");
+    assert_eq!(status, 404);
+    let (status, _, _) = server.http_get("audio/Sound.nx/Bgm00.img/nope");
+    assert_eq!(status, 404);
+}
+
+#[test]
+fn rest_serves_part_of_a_sound_for_a_range() {
+    let server = Server::start(&[]);
+    let mp3 = fixtures::mp3(10);
+    let len = mp3.len();
+    let get = |range: &str| server.http_get_with("audio/Sound.nx/Bgm00.img/Wrapped", &[("Range", range)]);
+
+    // Offsets are into the playable bytes, the WZ header isn't counted
+    let (status, headers, body) = get("bytes=10-19");
+    assert_eq!(status, 206);
+    assert_eq!(header(&headers, "content-range"), Some(format!("bytes 10-19/{}", len).as_str()));
+    assert_eq!(body, mp3[10..20]);
+
+    let (status, _, body) = get("bytes=4000-");
+    assert_eq!(status, 206);
+    assert_eq!(body, mp3[4000..]);
+
+    let (status, headers, body) = get("bytes=-5");
+    assert_eq!(status, 206);
+    assert_eq!(header(&headers, "content-range"), Some(format!("bytes {}-{}/{}", len - 5, len - 1, len).as_str()));
+    assert_eq!(body, mp3[len - 5..]);
+
+    // Ends past the end are cut short, starts past the end can't be served
+    let (status, _, body) = get(&format!("bytes={}-{}", len - 2, len + 100));
+    assert_eq!((status, body), (206, mp3[len - 2..].to_vec()));
+    let (status, headers, _) = get(&format!("bytes={}-", len));
+    assert_eq!(status, 416);
+    assert_eq!(header(&headers, "content-range"), Some(format!("bytes */{}", len).as_str()));
+
+    // Several ranges aren't handled, they get the whole thing
+    let (status, headers, body) = get("bytes=0-1,5-6");
+    assert_eq!((status, body), (200, mp3));
+    assert_eq!(header(&headers, "accept-ranges"), Some("bytes"));
+}
+
+#[test]
+fn audio_op_describes_a_sound() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("ws");
+
+    let audio = |ws: &mut common::Socket, id: u32, path: &str| {
+        let responses = request_json(ws, &Request { id, op: Op::Audio(path.to_string()) });
+        responses.into_iter().next().unwrap().result
+    };
+
+    match audio(&mut ws, 1, "Sound.nx/Bgm00.img/Wrapped") {
+        Ok(Payload::Audio(audio)) => {
+            assert_eq!(audio.mime, "audio/mpeg");
+            assert_eq!(audio.duration_ms, Some(261));
+            assert_eq!(audio.bytes as usize, fixtures::mp3(10).len());
+        }
+        result => panic!("Expected audio, got {:?}", result),
+    }
+
+    // Starts like an mp3 but there are no frames to time
+    match audio(&mut ws, 2, "Sound.nx/Bgm00.img/GoPicnic") {
+        Ok(Payload::Audio(audio)) => assert_eq!((audio.mime.as_str(), audio.duration_ms), ("audio/mpeg", None)),
+        result => panic!("Expected audio, got {:?}", result),
+    }
+
+    assert!(matches!(audio(&mut ws, 3, "Map.nx/Obj/login.img/obj/0"), Err(Error::NotFound(_))));
+}
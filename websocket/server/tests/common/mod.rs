@@ -0,0 +1,168 @@
+//! Starts the server binary on a free port against fixtures::sample() written out as .nx files
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+pub type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+/// Reads give up after this so a missing response fails the test instead of hanging it
+pub const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Server {
+    pub addr: String,
+    pub nx_dir: PathBuf,
+    child: Child,
+}
+
+impl Server {
+    /// Runs the server with the fixture files and args on top of --listen and --nx-dir
+    pub fn start(args: &[&str]) -> Server {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr = format!("127.0.0.1:{}", port);
+        let nx_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("nx-{}", port));
+        fixtures::sample().write(&nx_dir).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_server"))
+            .args(["--listen", &addr, "--nx-dir"])
+            .arg(&nx_dir)
+            .args(args)
+            .env_remove("NX_SERVER_CONFIG")
+            .env_remove("NX_DIR")
+            .env_remove("NX_TOKENS")
+            .env_remove("NX_CACHE_BYTES")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Can't start the server");
+
+        let start = Instant::now();
+        while TcpStream::connect(&addr).is_err() {
+            assert!(start.elapsed() < Duration::from_secs(10), "Server never started listening on {}", addr);
+            sleep(Duration::from_millis(50));
+        }
+
+        Server { addr, nx_dir, child }
+    }
+
+    /// route can have a query string on the end, eg "ws?chunk_bytes=1024"
+    pub fn url(&self, route: &str) -> String {
+        format!("ws://{}/{}", self.addr, route)
+    }
+
+    pub fn connect(&self, route: &str) -> Socket {
+        let (socket, _) = tungstenite::connect(self.url(route)).expect("Can't connect");
+        if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+            stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        }
+        socket
+    }
+
+    /// Plain HTTP GET of one of the REST routes- status, headers (names lowercased) and body
+    pub fn http_get(&self, route: &str) -> (u16, Vec<(String, String)>, Vec<u8>) {
+        self.http_get_with(route, &[])
+    }
+
+    /// http_get with extra request headers, eg ("Range", "bytes=0-9")
+    pub fn http_get_with(&self, route: &str, headers: &[(&str, &str)]) -> (u16, Vec<(String, String)>, Vec<u8>) {
+        let mut stream = TcpStream::connect(&self.addr).unwrap();
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        let extra: String = headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+        write!(stream, "GET /{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n{}\r\n", route, self.addr, extra).unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+
+        let split = response.windows(4).position(|x| x == b"\r\n\r\n").expect("No end of headers");
+        let head = String::from_utf8_lossy(&response[..split]).to_string();
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+        let headers = lines
+            .filter_map(|x| x.split_once(':'))
+            .map(|(name, value)| (name.to_lowercase(), value.trim().to_string()))
+            .collect();
+        (status, headers, response[split + 4..].to_vec())
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Waits for the process to exit by itself
+    pub fn wait(&mut self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if let Ok(Some(_)) = self.child.try_wait() {
+                return true;
+            }
+            sleep(Duration::from_millis(50));
+        }
+        false
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.nx_dir);
+    }
+}
+
+/// Value of the header called name (lowercase) in what http_get returned
+pub fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|(x, _)| x == name).map(|(_, value)| value.as_str())
+}
+
+/// Next data frame, skipping pings
+pub fn read(socket: &mut Socket) -> Message {
+    loop {
+        match socket.read().expect("Nothing came back") {
+            Message::Ping(_) | Message::Pong(_) => continue,
+            msg => return msg,
+        }
+    }
+}
+
+pub fn read_text(socket: &mut Socket) -> String {
+    match read(socket) {
+        Message::Text(text) => text,
+        msg => panic!("Expected a text frame, got {:?}", msg),
+    }
+}
+
+/// Sends request on a JSON route and reads responses until the one marked done
+pub fn request_json(socket: &mut Socket, request: &protocol::Request) -> Vec<protocol::Response> {
+    socket.send(Message::Text(serde_json::to_string(request).unwrap())).unwrap();
+    let mut responses: Vec<protocol::Response> = vec![];
+    while !responses.iter().any(|x| x.done) {
+        responses.push(serde_json::from_str(&read_text(socket)).unwrap());
+    }
+    responses
+}
+
+/// Sends request on /wsb and reads responses until the one marked done
+pub fn request_binary(socket: &mut Socket, request: &protocol::Request) -> Vec<protocol::Response> {
+    socket.send(Message::Binary(bincode::serialize(request).unwrap())).unwrap();
+    let mut responses: Vec<protocol::Response> = vec![];
+    while !responses.iter().any(|x| x.done) {
+        match read(socket) {
+            Message::Binary(bin) => responses.push(bincode::deserialize(&bin).unwrap()),
+            msg => panic!("Expected a binary frame, got {:?}", msg),
+        }
+    }
+    responses
+}
+
+pub fn get(id: u32, path: &str) -> protocol::Request {
+    protocol::Request {
+        id,
+        op: protocol::Op::Get(nx::WSRequest { path: path.to_string() }),
+    }
+}
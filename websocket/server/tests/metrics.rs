@@ -0,0 +1,96 @@
+mod common;
+
+use common::{get, header, read_text, request_binary, request_json, Server};
+use tungstenite::Message;
+
+/// Value of the line that starts with name (labels included), eg nx_requests_total{route="ws"}
+fn value(metrics: &str, name: &str) -> f64 {
+    metrics
+        .lines()
+        .find_map(|x| x.strip_prefix(name)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("No {} in\n{}", name, metrics))
+        .parse()
+        .unwrap()
+}
+
+fn scrape(server: &Server) -> String {
+    let (status, headers, body) = server.http_get("metrics");
+    assert_eq!(status, 200);
+    assert!(header(&headers, "content-type").unwrap().starts_with("text/plain"));
+    String::from_utf8(body).unwrap()
+}
+
+#[test]
+fn requests_show_up_in_counters_and_histograms() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("ws");
+
+    // The same node twice, the second time from the cache
+    for id in [1, 2] {
+        let responses = request_json(&mut ws, &get(id, "Map.nx/Back/grassySoil.img"));
+        assert!(responses[0].result.is_ok());
+    }
+    let responses = request_json(&mut ws, &get(3, "Map.nx/Obj/nope.img"));
+    assert!(responses[0].result.is_err());
+    let (status, _, body) = server.http_get("children/Map.nx");
+    assert_eq!(status, 200);
+
+    let metrics = scrape(&server);
+    assert!(metrics.contains("# TYPE nx_requests_total counter"));
+    assert!(metrics.contains("# TYPE nx_response_seconds histogram"));
+    assert_eq!(value(&metrics, "nx_connections_active{route=\"ws\"}"), 1.0);
+
+    assert_eq!(value(&metrics, "nx_requests_total{route=\"ws\"}"), 3.0);
+    assert_eq!(value(&metrics, "nx_responses_total{route=\"ws\"}"), 3.0);
+    assert_eq!(value(&metrics, "nx_errors_total{route=\"ws\"}"), 1.0);
+    assert_eq!(value(&metrics, "nx_refused_total{route=\"ws\"}"), 0.0);
+    assert!(value(&metrics, "nx_bytes_sent_total{route=\"ws\"}") > 0.0);
+
+    assert_eq!(value(&metrics, "nx_requests_total{route=\"children\"}"), 1.0);
+    assert_eq!(value(&metrics, "nx_bytes_sent_total{route=\"children\"}"), body.len() as f64);
+
+    // Buckets count everything at or under their bound so they only go up, ending at the count
+    let buckets: Vec<f64> = metrics
+        .lines()
+        .filter(|x| x.starts_with("nx_response_seconds_bucket{route=\"ws\","))
+        .map(|x| x.rsplit(' ').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(buckets.len(), 13);
+    assert!(buckets.windows(2).all(|x| x[0] <= x[1]));
+    assert_eq!(value(&metrics, "nx_response_seconds_bucket{route=\"ws\",le=\"+Inf\"}"), 3.0);
+    assert_eq!(value(&metrics, "nx_response_seconds_count{route=\"ws\"}"), 3.0);
+    assert!(value(&metrics, "nx_response_seconds_sum{route=\"ws\"}") > 0.0);
+
+    // Both misses are timed as lookups, and so is /children which skips the cache
+    assert_eq!(value(&metrics, "nx_cache_hits_total"), 1.0);
+    assert_eq!(value(&metrics, "nx_cache_misses_total"), 2.0);
+    assert_eq!(value(&metrics, "nx_lookup_seconds_count"), 3.0);
+    assert_eq!(value(&metrics, "nx_lookup_seconds_bucket{le=\"+Inf\"}"), 3.0);
+}
+
+#[test]
+fn requests_for_the_same_node_share_one_lookup() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("ws");
+    let path = "Map.nx/Map/Map1/100000000.img";
+
+    // All sent before any answer, the ones that miss wait on the first lookup instead of starting their own
+    for id in 1..=8 {
+        ws.send(Message::Text(serde_json::to_string(&get(id, path)).unwrap())).unwrap();
+    }
+    for _ in 1..=8 {
+        let response: protocol::Response = serde_json::from_str(&read_text(&mut ws)).unwrap();
+        assert!(response.result.is_ok());
+    }
+    let metrics = scrape(&server);
+    assert_eq!(value(&metrics, "nx_lookup_seconds_count"), 1.0);
+    assert_eq!(value(&metrics, "nx_cache_entries"), 1.0);
+
+    // bincode is cached apart from JSON, sized by its own length
+    let mut wsb = server.connect("wsb");
+    assert!(request_binary(&mut wsb, &get(9, path))[0].result.is_ok());
+    let metrics = scrape(&server);
+    assert_eq!(value(&metrics, "nx_lookup_seconds_count"), 2.0);
+    assert_eq!(value(&metrics, "nx_cache_entries"), 2.0);
+    assert_eq!(value(&metrics, "nx_cache_hits_total") + value(&metrics, "nx_cache_misses_total"), 9.0);
+}
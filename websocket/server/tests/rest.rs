@@ -0,0 +1,70 @@
+mod common;
+
+use common::{header, Server};
+
+#[test]
+fn node_is_the_whole_tree_or_down_to_depth() {
+    let server = Server::start(&[]);
+
+    let (status, headers, body) = server.http_get("node/Map.nx/Back/grassySoil.img");
+    assert_eq!(status, 200);
+    assert_eq!(header(&headers, "content-type"), Some("application/json"));
+    let node: nx::NodeSH = serde_json::from_slice(&body).unwrap();
+    let sprite = &node.children["back"].children["0"];
+    assert!(matches!(&sprite.data, nx::NodeDataPopulated::Bitmap { width: 16, height: 16, data } if !data.is_empty()));
+    assert!(sprite.children.contains_key("origin"));
+
+    // depth=2 stops at the sprite, and leaves its pixels out
+    let (status, _, body) = server.http_get("node/Map.nx/Back/grassySoil.img?depth=2");
+    assert_eq!(status, 200);
+    let node: nx::NodeSH = serde_json::from_slice(&body).unwrap();
+    let sprite = &node.children["back"].children["0"];
+    assert!(matches!(&sprite.data, nx::NodeDataPopulated::Bitmap { width: 16, height: 16, data } if data.is_empty()));
+    assert!(sprite.children.is_empty());
+}
+
+#[test]
+fn children_are_sorted_names() {
+    let server = Server::start(&[]);
+
+    let (status, _, body) = server.http_get("children/Map.nx");
+    assert_eq!(status, 200);
+    let names: Vec<String> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(names, ["Back", "Map", "Obj", "Tile"]);
+
+    let (status, _, body) = server.http_get("children/Map.nx/Obj/login.img/obj/0");
+    assert_eq!(status, 200);
+    assert_eq!(serde_json::from_slice::<Vec<String>>(&body).unwrap(), ["origin"]);
+}
+
+#[test]
+fn bitmap_is_a_png() {
+    let server = Server::start(&[]);
+
+    let (status, headers, body) = server.http_get("bitmap/Map.nx/Back/grassySoil.img/back/0.png");
+    assert_eq!(status, 200);
+    assert_eq!(header(&headers, "content-type"), Some("image/png"));
+    let reader = png::Decoder::new(&body[..]).read_info().unwrap();
+    assert_eq!((reader.info().width, reader.info().height), (16, 16));
+
+    let (_, _, body) = server.http_get("bitmap/Map.nx/Back/grassySoil.img/back/0.png?downscale=4");
+    let reader = png::Decoder::new(&body[..]).read_info().unwrap();
+    assert_eq!((reader.info().width, reader.info().height), (4, 4));
+}
+
+#[test]
+fn errors_have_matching_status_codes() {
+    let server = Server::start(&[]);
+
+    for (route, status) in [
+        ("node/Map.nx/Obj/nope.img", 404),
+        ("node/Character.nx/00002000.img", 404),
+        ("node/Map.nx/Obj/../Back", 400),
+        ("children/testingasdfsdfsdfdf", 400),
+        ("bitmap/Map.nx/Back/grassySoil.img/back/0", 404),
+        ("bitmap/Map.nx/Back/grassySoil.img/back.png", 404),
+    ] {
+        let (got, _, body) = server.http_get(route);
+        assert_eq!(got, status, "{} said {}", route, String::from_utf8_lossy(&body));
+    }
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use common::{read_text, Server};
+use protocol::{Error, Member, Presence, RoomEvent};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::Message;
+
+fn event(ws: &mut common::Socket) -> RoomEvent {
+    serde_json::from_str(&read_text(ws)).unwrap()
+}
+
+fn welcome(ws: &mut common::Socket) -> (u32, Vec<Member>) {
+    match event(ws) {
+        RoomEvent::Welcome { id, members } => (id, members),
+        event => panic!("Expected a Welcome, got {:?}", event),
+    }
+}
+
+fn presence(x: f32, y: f32) -> Presence {
+    Presence {
+        x,
+        y,
+        state: "walk".to_string(),
+    }
+}
+
+fn publish(ws: &mut common::Socket, presence: &Presence) {
+    ws.send(Message::Text(serde_json::to_string(presence).unwrap())).unwrap();
+}
+
+#[test]
+fn presences_reach_everyone_else_in_the_room() {
+    let server = Server::start(&[]);
+
+    let mut a = server.connect("room/lobby");
+    let (a_id, members) = welcome(&mut a);
+    assert!(members.is_empty());
+    publish(&mut a, &presence(0.5, 0.25));
+
+    let mut b = server.connect("room/lobby");
+    let (b_id, members) = welcome(&mut b);
+    assert_eq!(
+        members,
+        [Member {
+            id: a_id,
+            presence: Some(presence(0.5, 0.25)),
+        }]
+    );
+    assert_eq!(event(&mut a), RoomEvent::Joined { id: b_id });
+
+    // Someone in another room hears nothing from this one
+    let mut other = server.connect("room/elsewhere");
+    assert!(welcome(&mut other).1.is_empty());
+
+    publish(&mut b, &presence(-1.0, 1.0));
+    assert_eq!(
+        event(&mut a),
+        RoomEvent::Presence {
+            id: b_id,
+            presence: presence(-1.0, 1.0),
+        }
+    );
+
+    b.close(None).unwrap();
+    assert_eq!(event(&mut a), RoomEvent::Left { id: b_id });
+
+    // Nothing from other reaches a, the next thing a gets is the answer to its own bad frame
+    publish(&mut other, &presence(0.0, 0.0));
+    a.send(Message::Text("not a presence".to_string())).unwrap();
+    assert!(matches!(event(&mut a), RoomEvent::Error(Error::InvalidRequest(_))));
+}
+
+#[test]
+fn full_rooms_turn_clients_away() {
+    let server = Server::start(&["--max-room-members", "1", "--max-rooms", "2"]);
+
+    let mut a = server.connect("room/lobby");
+    welcome(&mut a);
+
+    let mut b = server.connect("room/lobby");
+    match b.read() {
+        Ok(Message::Close(Some(frame))) => assert_eq!(frame.code, CloseCode::Again),
+        msg => panic!("Expected a close frame, got {:?}", msg),
+    }
+
+    let mut c = server.connect("room/second");
+    welcome(&mut c);
+    let mut d = server.connect("room/third");
+    match d.read() {
+        Ok(Message::Close(Some(frame))) => assert_eq!(frame.code, CloseCode::Again),
+        msg => panic!("Expected a close frame, got {:?}", msg),
+    }
+
+    // Once a leaves there's room in lobby again, a few tries as it leaves just after the close
+    a.close(None).unwrap();
+    while a.read().is_ok() {}
+    for _ in 0..20 {
+        let mut e = server.connect("room/lobby");
+        if let Ok(Message::Text(text)) = e.read() {
+            assert!(matches!(serde_json::from_str(&text).unwrap(), RoomEvent::Welcome { .. }));
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    panic!("lobby never had room again");
+}
+
+#[test]
+fn room_ids_are_checked() {
+    let server = Server::start(&[]);
+
+    match tungstenite::connect(server.url(&format!("room/{}", "x".repeat(65)))) {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 400),
+        result => panic!("Expected a 400, got {:?}", result.map(|x| x.1)),
+    }
+}
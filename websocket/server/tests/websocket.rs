@@ -0,0 +1,461 @@
+mod common;
+
+use common::{get, read, read_text, request_binary, request_json, Server};
+use protocol::{Error, Op, Payload, Request};
+use std::io::Read;
+use std::time::Duration;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::Message;
+
+fn node(response: &protocol::Response) -> &nx::NodeSH {
+    match &response.result {
+        Ok(Payload::Node(node)) => node,
+        result => panic!("Expected a node for {}, got {:?}", response.path, result),
+    }
+}
+
+fn error(response: &protocol::Response) -> &Error {
+    match &response.result {
+        Err(e) => e,
+        result => panic!("Expected an error for {}, got {:?}", response.path, result),
+    }
+}
+
+#[test]
+fn ws_gets_a_node() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("ws");
+
+    let responses = request_json(&mut ws, &get(7, "Map.nx/Obj/login.img"));
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].id, 7);
+    assert_eq!(responses[0].path, "Map.nx/Obj/login.img");
+    let sprite = &node(&responses[0]).children["obj"].children["0"];
+    assert!(matches!(sprite.data, nx::NodeDataPopulated::Bitmap { width: 2, height: 2, .. }));
+}
+
+#[test]
+fn children_op_lists_names_kinds_and_counts() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("ws");
+
+    let children = |ws: &mut common::Socket, id, path: &str| {
+        let responses = request_json(ws, &Request { id, op: Op::Children(path.to_string()) });
+        assert_eq!((responses.len(), responses[0].id, responses[0].path.as_str()), (1, id, path));
+        responses.into_iter().next().unwrap().result
+    };
+    let child = |name: &str, kind, children| protocol::Child {
+        name: name.to_string(),
+        kind,
+        children,
+    };
+
+    let names: Vec<String> = match children(&mut ws, 1, "Map.nx/Map/Map1/100000000.img") {
+        Ok(Payload::Children(children)) => children.into_iter().map(|x| x.name).collect(),
+        result => panic!("Expected children, got {:?}", result),
+    };
+    assert_eq!(names, ["0", "back", "info"]);
+
+    match children(&mut ws, 2, "Map.nx/Obj/login.img/obj") {
+        Ok(Payload::Children(children)) => assert_eq!(children, [child("0", protocol::NodeKind::Bitmap, 1)]),
+        result => panic!("Expected children, got {:?}", result),
+    }
+    match children(&mut ws, 3, "Map.nx/Obj/login.img/obj/0") {
+        Ok(Payload::Children(children)) => assert_eq!(children, [child("origin", protocol::NodeKind::Vector, 0)]),
+        result => panic!("Expected children, got {:?}", result),
+    }
+    assert!(matches!(children(&mut ws, 4, "Map.nx/Obj/nope.img"), Err(Error::NotFound(_))));
+}
+
+#[test]
+fn tree_op_stops_at_depth_without_pixels() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("ws");
+
+    let tree = |ws: &mut common::Socket, id, path: &str, depth| {
+        let op = Op::Tree(protocol::TreeRequest { path: path.to_string(), depth });
+        let mut responses = request_json(ws, &Request { id, op });
+        assert_eq!((responses.len(), responses[0].id, responses[0].path.as_str()), (1, id, path));
+        responses.remove(0)
+    };
+
+    let response = tree(&mut ws, 1, "Map.nx/Map/Map1/100000000.img", 1);
+    let map = node(&response);
+    assert_eq!(map.children.len(), 3);
+    assert!(map.children.values().all(|x| x.children.is_empty()));
+
+    let response = tree(&mut ws, 2, "Map.nx/Map/Map1/100000000.img", 2);
+    assert!(matches!(
+        &node(&response).children["info"].children["bgm"].data,
+        nx::NodeDataPopulated::String(bgm) if bgm == "Bgm00/GoPicnic"
+    ));
+
+    // Deep enough for the origin, the bitmap is still only its size
+    let response = tree(&mut ws, 3, "Map.nx/Back/grassySoil.img", 3);
+    let sprite = &node(&response).children["back"].children["0"];
+    assert!(matches!(&sprite.data, nx::NodeDataPopulated::Bitmap { width: 16, height: 16, data } if data.is_empty()));
+    assert!(matches!(sprite.children["origin"].data, nx::NodeDataPopulated::Vector(8, 16)));
+
+    assert!(matches!(error(&tree(&mut ws, 4, "Map.nx/Obj/../Back", 1)), Error::PathTraversal(_)));
+}
+
+#[test]
+fn ws_answers_each_bad_path_with_its_own_error() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("ws");
+
+    let cases = [
+        ("Map.nx/Obj/nope.img", "NotFound"),
+        ("testingasdfsdfsdfdf", "MalformedPath"),
+        ("Map.nx/Obj/../Back", "PathTraversal"),
+        ("Character.nx/00002000.img", "FileNotLoaded"),
+    ];
+    for (i, (path, expected)) in cases.iter().enumerate() {
+        let responses = request_json(&mut ws, &get(i as u32 + 1, path));
+        let e = error(&responses[0]);
+        let kind = match e {
+            Error::NotFound(_) => "NotFound",
+            Error::MalformedPath(_) => "MalformedPath",
+            Error::PathTraversal(_) => "PathTraversal",
+            Error::FileNotLoaded(_) => "FileNotLoaded",
+            _ => "other",
+        };
+        assert_eq!(kind, *expected, "{} got {:?}", path, e);
+    }
+}
+
+#[test]
+fn ws_bundles_a_map_with_its_dependencies() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("ws");
+
+    let request = Request {
+        id: 1,
+        op: Op::BundleMap("100000000".to_string()),
+    };
+    let responses = request_json(&mut ws, &request);
+    let mut paths: Vec<&str> = responses.iter().map(|x| x.path.as_str()).collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        [
+            "Map.nx/Back/grassySoil.img",
+            "Map.nx/Map/Map1/100000000.img",
+            "Map.nx/Obj/login.img",
+            "Map.nx/Tile/woodMarble.img",
+            "Sound.nx/Bgm00.img/GoPicnic",
+        ]
+    );
+    assert!(responses.iter().all(|x| x.result.is_ok()));
+    assert_eq!(responses.iter().filter(|x| x.done).count(), 1);
+    assert!(responses.last().unwrap().done);
+}
+
+#[test]
+fn ws_answers_bare_requests_the_old_way() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("ws");
+
+    ws.send(Message::Text(r#"{"path":"Map.nx/Obj/login.img"}"#.to_string())).unwrap();
+    let node: nx::NodeSH = serde_json::from_str(&read_text(&mut ws)).unwrap();
+    assert!(node.children.contains_key("obj"));
+
+    ws.send(Message::Text(r#"{"path":"Map.nx/Obj/nope.img"}"#.to_string())).unwrap();
+    assert!(read_text(&mut ws).starts_with("ERROR Not found"));
+
+    ws.send(Message::Text("garbage".to_string())).unwrap();
+    assert!(read_text(&mut ws).starts_with("ERROR Invalid request"));
+}
+
+#[test]
+fn ws_streams_big_responses_in_chunks() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("ws?chunk_bytes=1024");
+
+    let request = Request {
+        id: 3,
+        op: Op::BundleMap("100000000".to_string()),
+    };
+    ws.send(Message::Text(serde_json::to_string(&request).unwrap())).unwrap();
+
+    // Only Back/grassySoil.img is over 1024 bytes
+    let mut streamed = 0;
+    let mut responses: Vec<protocol::Response> = vec![];
+    while !responses.iter().any(|x| x.done) {
+        let text = read_text(&mut ws);
+        let (bytes, chunks) = match serde_json::from_str::<protocol::Stream>(&text) {
+            Ok(protocol::Stream::Start { id, bytes, chunks, .. }) => {
+                assert_eq!(id, 3);
+                (bytes, chunks)
+            }
+            Ok(frame) => panic!("Expected Start, got {:?}", frame),
+            Err(_) => {
+                responses.push(serde_json::from_str(&text).unwrap());
+                continue;
+            }
+        };
+
+        let mut json = String::new();
+        for i in 0..chunks {
+            match serde_json::from_str::<protocol::Stream>(&read_text(&mut ws)).unwrap() {
+                protocol::Stream::Chunk { id, index, data } => {
+                    assert_eq!((id, index), (3, i));
+                    assert!(data.len() <= 1024);
+                    json.push_str(&data);
+                }
+                frame => panic!("Expected Chunk {}, got {:?}", i, frame),
+            }
+        }
+        assert_eq!(json.len(), bytes as usize);
+        responses.push(serde_json::from_str(&json).unwrap());
+        streamed += 1;
+    }
+
+    assert_eq!(streamed, 1);
+    assert_eq!(responses.len(), 5);
+    assert!(responses.iter().all(|x| x.result.is_ok()));
+}
+
+#[test]
+fn ws_deflated_answers_with_deflated_json() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("ws_deflated");
+
+    let inflate = |msg: Message| match msg {
+        Message::Binary(bin) => {
+            let mut text = String::new();
+            flate2::read::DeflateDecoder::new(&bin[..]).read_to_string(&mut text).unwrap();
+            text
+        }
+        msg => panic!("Expected a binary frame, got {:?}", msg),
+    };
+
+    ws.send(Message::Text(serde_json::to_string(&get(3, "Map.nx/Obj/login.img")).unwrap())).unwrap();
+    let response: protocol::Response = serde_json::from_str(&inflate(read(&mut ws))).unwrap();
+    assert_eq!((response.id, response.done), (3, true));
+    assert!(node(&response).children.contains_key("obj"));
+
+    // Bare requests get the old answers, deflated the same way
+    ws.send(Message::Text(r#"{"path":"Map.nx/Obj/nope.img"}"#.to_string())).unwrap();
+    assert!(inflate(read(&mut ws)).starts_with("ERROR Not found"));
+}
+
+#[test]
+fn wsb_gets_a_node_and_typed_errors() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("wsb");
+
+    let responses = request_binary(&mut ws, &get(1, "Map.nx/Back/grassySoil.img"));
+    assert!(node(&responses[0]).children.contains_key("back"));
+
+    let responses = request_binary(&mut ws, &get(2, "Map.nx/Back/nope.img"));
+    assert_eq!(responses[0].id, 2);
+    assert!(matches!(error(&responses[0]), Error::NotFound(_)));
+
+    ws.send(Message::Binary(vec![1, 2, 3])).unwrap();
+    match read(&mut ws) {
+        Message::Binary(bin) => {
+            let response: protocol::Response = bincode::deserialize(&bin).unwrap();
+            assert_eq!(response.id, protocol::UNKNOWN_ID);
+            assert!(matches!(error(&response), Error::InvalidRequest(_)));
+        }
+        msg => panic!("Expected a binary frame, got {:?}", msg),
+    }
+
+    ws.send(Message::Text("hello".to_string())).unwrap();
+    match read(&mut ws) {
+        Message::Binary(bin) => {
+            let response: protocol::Response = bincode::deserialize(&bin).unwrap();
+            assert!(matches!(error(&response), Error::InvalidRequest(_)));
+        }
+        msg => panic!("Expected a binary frame, got {:?}", msg),
+    }
+}
+
+#[test]
+fn wsb_decodes_bitmaps() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("wsb");
+
+    let request = Request {
+        id: 1,
+        op: Op::Bitmap(protocol::BitmapRequest {
+            path: "Map.nx/Obj/login.img/obj/0".to_string(),
+            format: protocol::BitmapFormat::Rgba,
+            downscale: 1,
+        }),
+    };
+    let responses = request_binary(&mut ws, &request);
+    match &responses[0].result {
+        Ok(Payload::Bitmap(bitmap)) => {
+            assert_eq!((bitmap.width, bitmap.height, bitmap.origin), (2, 2, (1, 2)));
+            assert_eq!(bitmap.data, [255, 0, 0, 255].repeat(4));
+        }
+        result => panic!("Expected a bitmap, got {:?}", result),
+    }
+}
+
+#[test]
+fn wst_streams_test_requests() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("wst");
+
+    ws.send(Message::Text("Hello WebSocket".to_string())).unwrap();
+    for _ in 0..3 {
+        match read(&mut ws) {
+            Message::Binary(bin) => {
+                let request: nx::WSRequest = bincode::deserialize(&bin).unwrap();
+                assert_eq!(request.path, "testingasdfsdfsdfdf");
+            }
+            msg => panic!("Expected a binary frame, got {:?}", msg),
+        }
+    }
+    ws.close(None).unwrap();
+}
+
+#[test]
+fn ping_op_gets_a_pong() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("ws");
+
+    let responses = request_json(&mut ws, &Request { id: 9, op: Op::Ping });
+    assert!(matches!(responses[0].result, Ok(Payload::Pong)));
+}
+
+#[test]
+fn client_close_is_answered() {
+    let server = Server::start(&[]);
+    let mut ws = server.connect("ws");
+
+    ws.close(None).unwrap();
+    loop {
+        match ws.read() {
+            Ok(Message::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => break,
+            Ok(_) => continue,
+            Err(e) => panic!("Expected the close to be answered, got {:?}", e),
+        }
+    }
+}
+
+/// Next close frame, failing on anything but pings and data frames before it
+fn close_frame(ws: &mut common::Socket) -> (CloseCode, String) {
+    loop {
+        match ws.read() {
+            Ok(Message::Close(Some(frame))) => return (frame.code, frame.reason.to_string()),
+            Ok(Message::Close(None)) => panic!("Closed without a reason"),
+            Ok(_) => continue,
+            Err(e) => panic!("Expected a close frame, got {:?}", e),
+        }
+    }
+}
+
+#[test]
+fn oversized_frames_close_the_connection() {
+    let server = Server::start(&["--max-frame-bytes", "64"]);
+    let mut ws = server.connect("ws");
+
+    ws.send(Message::Text("x".repeat(100))).unwrap();
+    // Turned away by tungstenite while reading it, not once it was all buffered
+    assert_eq!(close_frame(&mut ws), (CloseCode::Size, "Frame is over the 64 byte limit".to_string()));
+}
+
+#[test]
+fn requests_over_the_rate_limit_are_refused() {
+    let server = Server::start(&["--requests-per-second", "1", "--burst", "1"]);
+    let mut ws = server.connect("ws");
+
+    assert!(request_json(&mut ws, &get(1, "Map.nx/Obj/login.img"))[0].result.is_ok());
+    let responses = request_json(&mut ws, &get(2, "Map.nx/Obj/login.img"));
+    assert_eq!(responses[0].id, 2);
+    assert!(matches!(error(&responses[0]), Error::Refused(_)));
+}
+
+#[test]
+fn idle_connections_are_closed() {
+    let server = Server::start(&["--idle-timeout-secs", "1"]);
+    let mut ws = server.connect("ws");
+
+    assert_eq!(close_frame(&mut ws), (CloseCode::Normal, "Idle".to_string()));
+}
+
+#[test]
+fn tokens_are_required_when_configured() {
+    let server = Server::start(&["--tokens", "secret"]);
+
+    match tungstenite::connect(server.url("ws")) {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 401),
+        result => panic!("Expected a 401, got {:?}", result.map(|x| x.1)),
+    }
+    let mut ws = server.connect("ws?token=secret");
+    assert!(request_json(&mut ws, &get(1, "Map.nx/Obj/login.img"))[0].result.is_ok());
+}
+
+fn refused(server: &Server, route: &str) -> bool {
+    match tungstenite::connect(server.url(route)) {
+        Err(tungstenite::Error::Http(response)) => response.status() == 401,
+        _ => false,
+    }
+}
+
+#[test]
+fn tokens_are_form_urlencoded() {
+    let server = Server::start(&["--tokens", "a b&c+d=e%f"]);
+
+    for query in ["a+b%26c%2Bd%3De%25f", "a%20b%26c%2bd%3de%25f"] {
+        let mut ws = server.connect(&format!("ws?token={}", query));
+        assert!(request_json(&mut ws, &get(1, "Map.nx/Obj/login.img"))[0].result.is_ok(), "{}", query);
+    }
+    // + is a space, not a plus
+    assert!(refused(&server, "ws?token=a+b%26c+d%3De%25f"));
+    assert!(refused(&server, "ws?token=a+b"));
+}
+
+#[test]
+fn empty_tokens_in_the_config_file_are_dropped() {
+    let config = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("empty-token.toml");
+    std::fs::write(&config, "tokens = [\"\", \"secret\"]\n").unwrap();
+    let server = Server::start(&["--config", config.to_str().unwrap()]);
+
+    assert!(refused(&server, "ws?token="));
+    assert!(refused(&server, "ws"));
+    let mut ws = server.connect("ws?token=secret");
+    assert!(request_json(&mut ws, &get(1, "Map.nx/Obj/login.img"))[0].result.is_ok());
+}
+
+#[cfg(unix)]
+#[test]
+fn shutting_down_closes_connections() {
+    let mut server = Server::start(&[]);
+    let mut ws = server.connect("ws");
+    assert!(request_json(&mut ws, &get(1, "Map.nx/Obj/login.img"))[0].result.is_ok());
+
+    std::process::Command::new("kill")
+        .args(["-TERM", &server.pid().to_string()])
+        .status()
+        .unwrap();
+    assert_eq!(close_frame(&mut ws), (CloseCode::Away, "Server shutting down".to_string()));
+    assert!(server.wait(Duration::from_secs(10)));
+}
+
+#[test]
+fn hello_advertises_the_servers_version_ops_and_encodings() {
+    let server = Server::start(&["--routes", "ws,wsb"]);
+    let (mut ws, response) = tungstenite::connect(server.url("ws")).unwrap();
+    assert_eq!(response.headers()[protocol::VERSION_HEADER], protocol::VERSION.to_string().as_str());
+    assert_eq!(
+        response.headers()[protocol::SCHEMA_HEADER],
+        format!("{:016x}", protocol::schema()).as_str()
+    );
+
+    let request = Request {
+        id: 1,
+        op: Op::Hello(protocol::Hello::new(&["Get"], &["json"])),
+    };
+    let hello = match request_json(&mut ws, &request).remove(0).result {
+        Ok(Payload::Hello(hello)) => hello,
+        result => panic!("Expected a hello, got {:?}", result),
+    };
+    assert_eq!(protocol::check(&hello), Ok(()));
+    assert!(protocol::OPS.iter().all(|x| hello.supports(x)));
+    assert_eq!(hello.encodings, ["json", "bincode", "chunks"]);
+}